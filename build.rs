@@ -0,0 +1,21 @@
+use std::fs;
+
+/// versionで表示するビルド情報をコンパイル時に埋め込む
+/// TARGETはビルドスクリプトにのみ渡されるため､環境変数として本体に引き継ぐ
+/// 依存ライブラリのバージョンはリポジトリで管理しているCargo.lockから取得
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rustc-env=TARGET={}", std::env::var("TARGET").unwrap());
+
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for dep in ["nix", "rustyline"] {
+        let version = lock
+            .split("[[package]]")
+            .find(|p| p.lines().any(|l| l == format!("name = \"{dep}\"")))
+            .and_then(|p| p.lines().find_map(|l| l.strip_prefix("version = ")))
+            .map(|v| v.trim_matches('"'))
+            .unwrap_or("不明");
+        println!("cargo:rustc-env={}_VERSION={}", dep.to_uppercase(), version);
+    }
+}
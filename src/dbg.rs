@@ -1,20 +1,110 @@
-use std::{ffi::{c_void, CString}};
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CString},
+    fs,
+    io::{self, Write},
+    time::Instant,
+};
 
 use nix::{
     sys::{
-        wait::{waitpid, WaitStatus}, ptrace,
+        wait::{waitpid, WaitStatus}, ptrace, signal::{self, Signal},
+    },
+    sys::{
+        personality::{self, Persona},
+        resource::{setrlimit, Resource, RLIM_INFINITY},
     },
-    unistd::{execvp, fork, ForkResult, Pid}, libc::c_char,
+    fcntl::{open, OFlag},
+    sys::stat::Mode,
+    unistd::{close, dup2, execvp, fork, isatty, pipe2, read, setsid, write, ForkResult, Pid},
+    libc::{_exit, ioctl, rlim_t, siginfo_t, user_regs_struct, TIOCSCTTY},
+    errno::Errno,
 };
 
-use crate::helper::DynError;
+use crate::{elf, helper::DynError, maps, procfs};
+
+/// 子プロセスの実行を再開または終了するコマンド
+/// stop-hookから呼ばれると停止処理が再帰するため､stop-hookには設定できない
+const RESUME_CMDS: [&str; 8] = ["run", "r", "starti", "continue", "c", "stepi", "s", "exit"];
+
+/// stop-hookに設定できるコマンド
+/// ここにないコマンドは停止のたびに何も実行されず無視されるため､設定時に拒否する
+const HOOK_CMDS: [&str; 24] = [
+    "break", "b", "clear", "info", "registers", "regs", "telescope", "tel", "context", "ctx",
+    "vmmap", "patch", "nop", "argv", "auxv", "retaddr", "regsnap", "regdiff", "save",
+    "diff-registers", "set", "help", "h", "version",
+];
+
+/// contextで表示可能なセクション
+const CONTEXT_SECTIONS: [&str; 3] = ["regs", "code", "stack"];
+
+/// retaddrでスキャンするスタックのワード数の既定値
+const RETADDR_DEPTH: u64 = 256;
+
+/// telescopeでポインタをたどる最大の深さ
+const TELESCOPE_DEPTH: usize = 4;
+
+/// nopで一度に書き換えられる最大のバイト数
+const NOP_MAX: u64 = 4096;
+
+/// read_bytesで一度に読み込める最大のバイト数
+const READ_MAX: usize = 0x100_0000;
+
+/// SIGTRAPのsi_code
+/// x86-64のLinuxでは"int 3"によるトラップはSI_KERNELとして通知される
+const SI_KERNEL: i32 = 0x80;
+const TRAP_BRKPT: i32 = 1;
 
 /// デバッガ内の情報
 pub struct DbgInfo {
     pid: Pid,
     brk_addr: Option<*mut c_void>,
     brk_val: i64,
+
+    /// ブレークポイントのアドレスで停止中か
+    /// trueの場合､再開時は"int 3"を外して1ステップ実行してから再設定する
+    /// exec直後はripがエントリポイントでもまだ実行していないためfalse
+    brk_stopped: bool,
+    brk_inserted: bool, // 子プロセスのメモリに"int 3"を書き込んでいるか
     filename: String,
+    pretty_print_regs: bool, // レジスタを表形式で表示するか
+    context_sections: Vec<String>, // contextで表示するセクション
+    stop_regs: Option<user_regs_struct>, // 直近の停止時のレジスタ
+    prev_regs: Option<user_regs_struct>, // その1つ前の停止時のレジスタ
+    confirm_kill: bool, // 実行中にexitする際に確認するか
+    verbose: bool, // ptraceによる操作を逐一表示するか (メモリの読み込みは除く)
+    entry_sp: Option<u64>, // exec直後のrsp (argcの位置)
+    rlimits: Vec<(Resource, rlim_t)>, // exec前に子プロセスに設定するリソース制限
+    osabi_linux: bool, // set osabi linuxでOS ABIの確認を省略するか
+    stop_hook: Vec<String>, // 停止するたびに実行するコマンド
+    charset: Charset, // 文字列を表示する際の文字コード
+
+    /// 停止中もブレークポイントの"int 3"をメモリに残すか
+    /// falseの場合はブレークポイントで停止した時点で元の値に戻し､再開時に再設定する
+    /// trueの場合は停止中にメモリを読むと0xccが見えるが､
+    /// 停止中に他の経路でそのアドレスが実行されても確実にトラップできる
+    always_inserted: bool,
+    maintenance_time: bool, // コマンドごとの実行時間を表示するか
+    reg_snapshots: HashMap<String, user_regs_struct>, // regsnapで保存したレジスタ
+    inferior_tty: Option<String>, // 子プロセスの標準入出力に使う端末
+    exec_wrapper: Option<Vec<String>>, // 子プロセスを起動するラッパーコマンド
+}
+
+/// 文字列を表示する際の文字コード
+#[derive(Clone, Copy)]
+enum Charset {
+    Ascii,
+    Utf8,
+    Latin1,
+}
+
+/// exec直後のスタックに置かれたプロセスの初期情報
+/// rspの位置から argc, argv[], NULL, envp[], NULL, auxv[] の順に並ぶ
+struct InitialStack {
+    argc: Option<u64>, // 読み込めなかった場合はNone
+    argv: Vec<u64>,
+    envp: Vec<u64>,
+    auxv: Vec<(u64, u64)>,
 }
 
 /// デバッガ
@@ -26,9 +116,32 @@ pub struct ZDbg<T> {
 }
 
 /// デバッガの状態
-pub struct Running; // 実行している
+/// Runningは子プロセスのPIDを持ち､破棄された際に子プロセスをkillして回収する
+/// Dropは型引数を限定したZDbg<Running>には実装できないため､状態の側に実装する
+pub struct Running {
+    pid: Pid, // 実行している子プロセス
+}
 pub struct NotRunning; // 実行していない
 
+/// 子プロセスの終了を確認しないままRunningが破棄された場合､
+/// つまりデバッガや子プロセスの起動処理がエラーやpanicで終了した場合に､
+/// 停止したままのトレース対象やゾンビプロセスを残さないようkillして回収
+/// 子プロセスを回収済みの場合はdisarmでこの処理を行わないようにする
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = signal::kill(self.pid, Signal::SIGKILL);
+        let _ = waitpid(self.pid, None);
+    }
+}
+
+impl Running {
+    /// 子プロセスを回収済みの場合に呼び出し､破棄時にkillしないようにする
+    /// 回収後のPIDは別のプロセスに再利用される可能性がある
+    fn disarm(self) {
+        std::mem::forget(self);
+    }
+}
+
 /// デバッガの状態の列挙型表現
 /// Exitの場合終了
 pub enum State {
@@ -37,17 +150,38 @@ pub enum State {
     Exit,
 }
 
+impl State {
+    /// 状態に応じてコマンドを実行
+    /// set maintenance-time onの場合はコマンドの実行にかかった時間を表示
+    pub fn do_cmd(self, cmd: &[&str]) -> Result<State, DynError> {
+        let start = Instant::now();
+        let state = match self {
+            State::Running(r) => r.do_cmd(cmd)?,
+            State::NotRunning(n) => n.do_cmd(cmd)?,
+            State::Exit => State::Exit,
+        };
+
+        let info = match &state {
+            State::Running(r) => &r.info,
+            State::NotRunning(n) => &n.info,
+            State::Exit => return Ok(state),
+        };
+        if info.maintenance_time && !cmd.is_empty() {
+            println!("<<{} : {:?}>>", cmd.join(" "), start.elapsed());
+        }
+
+        Ok(state)
+    }
+}
+
 /// RunningとNotRunningで共通の実装
 impl<T> ZDbg<T> {
     /// ブレークポイントのアドレスを設定する関数
     /// 子プロセスのメモリ上には反映しない
     /// アドレス設定に成功した場合はtrueを返す
     fn set_break_addr(&mut self, cmd: &[&str]) -> bool {
-        if self.info.brk_addr.is_some() {
-            eprintln!(
-                "<<ブレークポイントは設定済みです : Addr = {:p}>>",
-                self.info.brk_addr.unwrap()
-            );
+        if let Some(addr) = self.info.brk_addr {
+            eprintln!("<<ブレークポイントは設定済みです : Addr = {:p}>>", addr);
             false
         } else if let Some(addr) = get_break_addr(cmd) {
             self.info.brk_addr = Some(addr);
@@ -57,16 +191,260 @@ impl<T> ZDbg<T> {
         }
     }
 
-    ///
-    fn get_break_addr(self, cmd: &[&str]) -> Option<*mut c_void> {}
+    /// set inferior-rlimit <名前> <値>を実行
+    /// 設定した制限は次回のrun時にexecの前に適用される
+    fn set_rlimit(&mut self, cmd: &[&str]) {
+        if cmd.len() < 4 {
+            eprintln!("<<引数が足りません : set inferior-rlimit <名前> <値|unlimited>>>");
+            return;
+        }
+
+        let resource = match cmd[2] {
+            "core" => Resource::RLIMIT_CORE,
+            "cpu" => Resource::RLIMIT_CPU,
+            "data" => Resource::RLIMIT_DATA,
+            "fsize" => Resource::RLIMIT_FSIZE,
+            "nofile" => Resource::RLIMIT_NOFILE,
+            "stack" => Resource::RLIMIT_STACK,
+            "as" => Resource::RLIMIT_AS,
+            _ => {
+                eprintln!(
+                    "<<不明なリソースです : {}, 指定可能なリソース : core cpu data fsize nofile stack as>>",
+                    cmd[2]
+                );
+                return;
+            }
+        };
+
+        let limit = if cmd[3] == "unlimited" {
+            RLIM_INFINITY
+        } else {
+            match parse_num(cmd[3]) {
+                Some(n) => n as rlim_t,
+                None => return,
+            }
+        };
+
+        self.info.rlimits.retain(|(r, _)| *r != resource);
+        self.info.rlimits.push((resource, limit));
+    }
+
+    /// 指定アドレスのブレークポイントのアドレス設定を解除
+    /// 子プロセスのメモリ上には反映しない
+    /// 解除したブレークポイントの数を返す
+    fn clear_break_addr(&mut self, addr: u64) -> usize {
+        if self.info.brk_addr == Some(addr as *mut c_void) {
+            self.info.brk_addr = None;
+            self.info.brk_stopped = false;
+            self.info.brk_inserted = false;
+            1
+        } else {
+            0
+        }
+    }
+
+    /// set inferior-tty <パス>を実行
+    /// noneを指定するとデバッガと同じ端末に戻す
+    fn set_inferior_tty(&mut self, path: &str) {
+        if path == "none" {
+            self.info.inferior_tty = None;
+            return;
+        }
+
+        match open_tty(path) {
+            Ok(fd) => {
+                let _ = close(fd);
+                self.info.inferior_tty = Some(path.to_string());
+            }
+            Err(e) => eprintln!("<<端末として使用できません : {path}, {e}>>"),
+        }
+    }
+
+    /// set verbose onの場合､デバッガ内部の低レベルな操作を表示
+    fn verbose(&self, msg: &str) {
+        if self.info.verbose {
+            println!("<<verbose : {msg}>>");
+        }
+    }
+
+    // 以下はself.info.pidの子プロセスに対するptraceのラッパー
+    // set verbose onの場合は実行するリクエストを表示する
+    // メモリの読み込み (PTRACE_PEEKDATA) は回数が多いため表示しない
+
+    fn getregs(&self) -> nix::Result<user_regs_struct> {
+        self.verbose("PTRACE_GETREGS");
+        ptrace::getregs(self.info.pid)
+    }
+
+    fn setregs(&self, regs: user_regs_struct) -> nix::Result<()> {
+        self.verbose(&format!("PTRACE_SETREGS (rip = {:#x})", regs.rip));
+        ptrace::setregs(self.info.pid, regs)
+    }
+
+    /// addrに1ワード書き込む
+    fn poke(&self, addr: u64, val: i64) -> nix::Result<()> {
+        self.verbose(&format!("PTRACE_POKEDATA (addr = {:#x}, data = {:#018x})", addr, val as u64));
+        unsafe { ptrace::write(self.info.pid, addr as *mut c_void, val as *mut c_void) }
+    }
+
+    fn cont(&self, sig: Option<Signal>) -> nix::Result<()> {
+        match sig {
+            Some(sig) => self.verbose(&format!("PTRACE_CONT (sig = {sig})")),
+            None => self.verbose("PTRACE_CONT"),
+        }
+        ptrace::cont(self.info.pid, sig)
+    }
+
+    fn step(&self) -> nix::Result<()> {
+        self.verbose("PTRACE_SINGLESTEP");
+        ptrace::step(self.info.pid, None)
+    }
+
+    fn getsiginfo(&self) -> nix::Result<siginfo_t> {
+        self.verbose("PTRACE_GETSIGINFO");
+        ptrace::getsiginfo(self.info.pid)
+    }
+
+    fn setoptions(&self, options: ptrace::Options) -> nix::Result<()> {
+        self.verbose(&format!("PTRACE_SETOPTIONS ({:?})", options));
+        ptrace::setoptions(self.info.pid, options)
+    }
+
+    fn kill(&self) -> nix::Result<()> {
+        self.verbose("PTRACE_KILL");
+        ptrace::kill(self.info.pid)
+    }
 
     /// 共通のコマンドを実行
-    fn do_cmd_common(&self, cmd: &[&str]) {
+    fn do_cmd_common(&mut self, cmd: &[&str]) {
         match cmd[0] {
             "help" | "h" => do_help(),
+            "version" => do_version(),
+            "set" => self.do_set(cmd),
+            "info" => self.do_info(cmd),
             _ => (),
         }
     }
+
+    /// infoコマンドでデバッガの情報を表示
+    fn do_info(&self, cmd: &[&str]) {
+        match cmd.get(1) {
+            Some(&"hooks") => {
+                if self.info.stop_hook.is_empty() {
+                    println!("<<stop-hookは設定されていません>>");
+                }
+                for (i, hook) in self.info.stop_hook.iter().enumerate() {
+                    println!("stop-hook[{i}] : {hook}");
+                }
+            }
+            // info procは実行中のみRunning側で処理する
+            Some(&"proc") => eprintln!("<<ターゲットを実行していません｡ runで実行してください｡>>"),
+            _ => eprintln!("<<不明なinfoコマンドです : info hooks|proc>>"),
+        }
+    }
+
+    /// set stop-hook <コマンド> [; <コマンド>]...を実行
+    /// set stop-hook noneで解除
+    fn set_stop_hook(&mut self, cmd: &[&str]) {
+        if cmd[2..] == ["none"] {
+            self.info.stop_hook.clear();
+            return;
+        }
+
+        // "regs; vmmap 0x0"のように;が単独の引数になっていない場合も区切る
+        let hooks: Vec<String> = cmd[2..]
+            .join(" ")
+            .split(';')
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .map(|c| c.to_string())
+            .collect();
+
+        for h in hooks.iter() {
+            let name = h.split(' ').next().unwrap_or("");
+            if RESUME_CMDS.contains(&name) {
+                eprintln!("<<実行を再開するコマンドはstop-hookに設定できません : {h}>>");
+                return;
+            }
+            if !HOOK_CMDS.contains(&name) {
+                eprintln!("<<不明なコマンドです : {h}>>");
+                return;
+            }
+        }
+
+        self.info.stop_hook = hooks;
+    }
+
+    /// setコマンドでデバッガの設定を変更
+    fn do_set(&mut self, cmd: &[&str]) {
+        if cmd.len() < 3 {
+            eprintln!("<<引数が足りません : set <設定名> <値>>>");
+            return;
+        }
+
+        match cmd[1] {
+            "pretty-print-regs" => {
+                if let Some(b) = parse_on_off(cmd[2]) {
+                    self.info.pretty_print_regs = b;
+                }
+            }
+            "maintenance-time" => {
+                if let Some(b) = parse_on_off(cmd[2]) {
+                    self.info.maintenance_time = b;
+                }
+            }
+            "verbose" => {
+                if let Some(b) = parse_on_off(cmd[2]) {
+                    self.info.verbose = b;
+                }
+            }
+            "inferior-rlimit" => self.set_rlimit(cmd),
+            "stop-hook" => self.set_stop_hook(cmd),
+            "inferior-tty" => self.set_inferior_tty(cmd[2]),
+            "exec-wrapper" => {
+                if cmd[2..] == ["none"] {
+                    self.info.exec_wrapper = None;
+                } else {
+                    self.info.exec_wrapper =
+                        Some(cmd[2..].iter().map(|s| s.to_string()).collect());
+                }
+            }
+            "breakpoint" => {
+                if cmd.len() < 4 || cmd[2] != "always-inserted" {
+                    eprintln!("<<引数が不正です : set breakpoint always-inserted on|off>>");
+                } else if let Some(b) = parse_on_off(cmd[3]) {
+                    self.info.always_inserted = b;
+                }
+            }
+            "charset" => match cmd[2] {
+                "ascii" => self.info.charset = Charset::Ascii,
+                "utf-8" => self.info.charset = Charset::Utf8,
+                "latin1" => self.info.charset = Charset::Latin1,
+                _ => eprintln!("<<ascii, utf-8, latin1 のいずれかを指定してください : {}>>", cmd[2]),
+            },
+            "osabi" => match cmd[2] {
+                "auto" => self.info.osabi_linux = false,
+                "linux" => self.info.osabi_linux = true,
+                _ => eprintln!("<<auto か linux を指定してください : {}>>", cmd[2]),
+            },
+            "confirm-kill" => {
+                if let Some(b) = parse_on_off(cmd[2]) {
+                    self.info.confirm_kill = b;
+                }
+            }
+            "context-sections" => {
+                if let Some(s) = cmd[2..].iter().find(|s| !CONTEXT_SECTIONS.contains(s)) {
+                    eprintln!(
+                        "<<不明なセクションです : {s}, 指定可能なセクション : {}>>",
+                        CONTEXT_SECTIONS.join(" ")
+                    );
+                    return;
+                }
+                self.info.context_sections = cmd[2..].iter().map(|s| s.to_string()).collect();
+            }
+            _ => eprintln!("<<不明な設定です : {}>>", cmd[1]),
+        }
+    }
 }
 
 /// NotRunning時に呼び出し可能なメソッド
@@ -77,22 +455,51 @@ impl ZDbg<NotRunning> {
                 pid: Pid::from_raw(0),
                 brk_addr: None,
                 brk_val: 0,
+                brk_stopped: false,
+                brk_inserted: false,
                 filename,
+                pretty_print_regs: false,
+                context_sections: CONTEXT_SECTIONS.iter().map(|s| s.to_string()).collect(),
+                stop_regs: None,
+                prev_regs: None,
+                confirm_kill: false,
+                verbose: false,
+                entry_sp: None,
+                rlimits: Vec::new(),
+                osabi_linux: false,
+                stop_hook: Vec::new(),
+                charset: Charset::Utf8,
+                always_inserted: false,
+                maintenance_time: false,
+                reg_snapshots: HashMap::new(),
+                inferior_tty: None,
+                exec_wrapper: None,
             }),
             _state: NotRunning,
         }
     }
 
-    pub fn do_cmd(self, cmd: &[&str]) -> Result<State, DynError> {
+    pub fn do_cmd(mut self, cmd: &[&str]) -> Result<State, DynError> {
         if cmd.is_empty() {
             return Ok(State::NotRunning(self));
         }
 
         match cmd[0] {
             "run" | "r" => return self.do_run(cmd),
+            "starti" => return self.do_starti(cmd),
             "break" | "b" => self.do_break(cmd),
+            "clear" => {
+                if cmd.len() < 2 {
+                    eprintln!("<<アドレスを指定してください : clear <addr>>>");
+                } else if let Some(addr) = parse_num(cmd[1]) {
+                    let n = self.clear_break_addr(addr);
+                    println!("<<{n}個のブレークポイントを削除しました>>");
+                }
+            }
             "exit" => return Ok(State::Exit),
-            "continue" | "c" | "stepi" | "s" | "registers" | "regs" => {
+            "continue" | "c" | "stepi" | "s" | "registers" | "regs" | "telescope" | "tel"
+            | "context" | "ctx" | "vmmap" | "patch" | "nop" | "argv" | "auxv"
+            | "retaddr" | "regsnap" | "regdiff" | "save" | "diff-registers" => {
                 eprintln!("<<ターゲットを実行していません｡ runで実行してください｡>>")
             }
             _ => self.do_cmd_common(cmd),
@@ -101,59 +508,294 @@ impl ZDbg<NotRunning> {
         Ok(State::NotRunning(self))
     }
 
+    /// ブレークポイントのアドレスのみを設定
+    /// メモリへの書き込みはrun時に行う
+    fn do_break(&mut self, cmd: &[&str]) {
+        if self.set_break_addr(cmd) {
+            println!("<<ブレークポイントを設定しました : Addr = {:p}>>", self.info.brk_addr.unwrap());
+        }
+    }
+
+    /// runを実行
+    /// ブレークポイントを書き込んだ後､実行を再開する
+    fn do_run(self, cmd: &[&str]) -> Result<State, DynError> {
+        if !self.check_target() {
+            return Ok(State::NotRunning(self));
+        }
+
+        let mut dbg = self.spawn(cmd)?;
+        dbg.set_break()?; // continue前にブレークポイントを設定
+        dbg.do_continue()
+    }
+
+    /// startiを実行
+    /// 実行を再開せず､exec直後の最初の命令で停止したままRunning状態に遷移
+    /// 動的リンクされたプログラムの場合は動的リンカのエントリポイントで停止する
+    fn do_starti(self, cmd: &[&str]) -> Result<State, DynError> {
+        if !self.check_target() {
+            return Ok(State::NotRunning(self));
+        }
+
+        let mut dbg = self.spawn(cmd)?;
+        dbg.set_break()?;
+
+        let regs = dbg.getregs()?;
+        let path = maps::read_maps(dbg.info.pid)?
+            .into_iter()
+            .find(|e| e.contains(regs.rip))
+            .map(|e| e.path)
+            .unwrap_or_default();
+        println!("<<最初の命令で停止しました : PC = {:#x} {}>>", regs.rip, path);
+
+        // 次のstepiで変化したレジスタを表示できるよう記録
+        // ブレークポイントにはまだヒットしていないため､on_stopは使わない
+        dbg.info.prev_regs = dbg.info.stop_regs.replace(regs);
+        dbg.run_stop_hook()
+    }
+
+    /// 実行ファイルがこのデバッガで扱えるLinux x86-64のELFかを確認
+    /// 他のアーキテクチャやOS向けのELFを実行してptraceやpersonalityが
+    /// 分かりにくい失敗をする前に､理由を表示して中止する
+    /// ELFでないもの (Mach-OやPEなど) もexecvpが/bin/shでスクリプトとして
+    /// 実行してしまうため中止する
+    /// ファイルが見つからない場合や#!スクリプト､読み込めない場合はexecvpに任せる
+    fn check_target(&self) -> bool {
+        let path = match elf::find_executable(&self.info.filename) {
+            Some(path) => path,
+            None => return true,
+        };
+
+        let header = match elf::read_executable(&path) {
+            elf::Executable::Elf(header) => header,
+            elf::Executable::Script | elf::Executable::Unreadable => return true,
+            elf::Executable::NotElf => {
+                eprintln!("<<ELFファイルではありません : {}>>", path.display());
+                return false;
+            }
+        };
+
+        if header.class != elf::ELFCLASS64 || header.machine != elf::EM_X86_64 {
+            eprintln!("<<x86-64の64ビットELFではありません : {}>>", path.display());
+            return false;
+        }
+
+        if !self.info.osabi_linux
+            && header.osabi != elf::ELFOSABI_SYSV
+            && header.osabi != elf::ELFOSABI_LINUX
+        {
+            eprintln!(
+                "<<Linux以外のOS ABI ({}) 向けのELFです｡実行するにはset osabi linuxを設定してください>>",
+                header.osabi_name()
+            );
+            return false;
+        }
+
+        true
+    }
+
     /// 子プロセスを生成し､成功した場合はRunning状態に遷移
-    fn do_run(mut self, cmd: &[&str]) -> Result<State, DynError> {
+    /// 子プロセスはexecvp直後のSIGTRAPで停止しており､
+    /// この時点ではまだエントリポイントも動的リンカも実行されていない
+    /// ブレークポイントはここで書き込むため､_startに設定したものも有効になる
+    fn spawn(mut self, cmd: &[&str]) -> Result<ZDbg<Running>, DynError> {
         // 子プロセスに渡すコマンドライン引数
-        let args: Vec<CString> = cmd.iter().map(|s| CString::new(*s).unwrap()).collect();
+        // exec-wrapperが設定されている場合は "wrapper... filename args..." として
+        // ラッパーを実行し､ラッパーがexecした先のプログラムをデバッグする
+        let (file, args): (&str, Vec<CString>) = match &self.info.exec_wrapper {
+            Some(wrapper) => {
+                let args = wrapper
+                    .iter()
+                    .map(|s| s.as_str())
+                    .chain([self.info.filename.as_str()])
+                    .chain(cmd[1..].iter().copied())
+                    .map(|s| CString::new(s).unwrap())
+                    .collect();
+                (wrapper[0].as_str(), args)
+            }
+            None => {
+                let args = cmd.iter().map(|s| CString::new(*s).unwrap()).collect();
+                (self.info.filename.as_str(), args)
+            }
+        };
+
+        // exec失敗時に子プロセスのエラーメッセージを受け取るパイプ
+        // close-on-execのため､execに成功すると書き込み側は自動的に閉じられる
+        let (pipe_r, pipe_w) = pipe2(OFlag::O_CLOEXEC)?;
 
         match unsafe { fork()? } {
             ForkResult::Child => {
+                let _ = close(pipe_r);
+
+                // 標準入出力を指定された端末に接続
+                // 失敗した場合はデバッガと同じ端末のまま実行する
+                if let Some(path) = &self.info.inferior_tty {
+                    match open_tty(path) {
+                        Ok(fd) => {
+                            // /dev/ttyやジョブ制御､Ctrl-Cがデバッガ側の端末に向かわないよう､
+                            // 新しいセッションを作ってこの端末を制御端末にする
+                            if let Err(e) = set_controlling_tty(fd) {
+                                eprintln!("<<制御端末に設定できません : {path}, {e}>>");
+                            }
+                            for target in 0..=2 {
+                                let _ = dup2(fd, target);
+                            }
+                            let _ = close(fd);
+                        }
+                        Err(e) => eprintln!("<<端末を開けません : {path}, {e}>>"),
+                    }
+                }
+
                 // ASLRを無効に
-                let p = personality::get().unwrap();
-                personality::set(p | Persona::ADDR_NO_RANDOMIZE).unwrap();
-                ptrace::traceme().unwrap();
+                // seccompなどで禁止されている場合はパニックせず親プロセスに理由を伝える
+                let aslr = personality::get()
+                    .and_then(|p| personality::set(p | Persona::ADDR_NO_RANDOMIZE));
+                if let Err(e) = aslr {
+                    exit_child(pipe_w, &format!("personalityに失敗 : {e}"));
+                }
+
+                // リソース制限を設定
+                for (resource, limit) in self.info.rlimits.iter() {
+                    if let Err(e) = setrlimit(*resource, *limit, *limit) {
+                        eprintln!("<<setrlimitに失敗 : {e}, {:?}>>", resource);
+                    }
+                }
+
+                if let Err(e) = ptrace::traceme() {
+                    exit_child(pipe_w, &format!("ptrace::tracemeに失敗 : {e}"));
+                }
 
                 // exec
-                execvp(&CString::new(self.info.filename.as_str()).unwrap(), &args).unwrap();
-                unreachable!();
-            }
-            ForkResult::Parent { child } => match waitpid(child, None)? {
-                WaitStatus::Stopped(..) => {
-                    println!("<<子プロセスの実行に成功しました : PID = {child}>>");
-                    self.info.pid = child;
-                    let mut dbg = ZDbg::<Running> {
-                        info: self.info,
-                        _state: Running,
-                    };
-                    dbg.set_break(); // ブレークポイントを設定
-                    dbg.do_continue()
+                let Err(e) = execvp(&CString::new(file).unwrap(), &args);
+                exit_child(pipe_w, &format!("execvpに失敗 : {e}"));
+            }
+            ForkResult::Parent { child } => {
+                // 以降の処理がエラーで中断しても子プロセスを残さないよう､
+                // fork直後から破棄時にkillして回収する対象とする
+                let state = Running { pid: child };
+                self.info.pid = child;
+                let _ = close(pipe_w);
+
+                let status = match (waitpid(child, None), &self.info.exec_wrapper) {
+                    (Ok(status @ WaitStatus::Stopped(..)), Some(_)) => {
+                        self.follow_wrapper_exec(status)
+                    }
+                    (status, _) => status.map_err(|e| e.into()),
+                };
+                let result = match status {
+                    Ok(status) => self.on_spawned(state, status, pipe_r),
+                    Err(e) => Err(e),
+                };
+                let _ = close(pipe_r);
+                result
+            }
+        }
+    }
+
+    /// exec-wrapperで起動したラッパーが対象のプログラムをexecするまで実行
+    /// statusはラッパー自身のexec直後の停止
+    /// ラッパーが自分でexecせずforkした先で実行する場合は追跡できない
+    fn follow_wrapper_exec(&self, status: WaitStatus) -> Result<WaitStatus, DynError> {
+        self.setoptions(ptrace::Options::PTRACE_O_TRACEEXEC)?;
+
+        let mut status = status;
+        loop {
+            // ラッパーに届いたシグナルはそのまま渡す
+            let sig = match status {
+                WaitStatus::Stopped(_, Signal::SIGTRAP) => None,
+                WaitStatus::Stopped(_, sig) => Some(sig),
+                _ => None,
+            };
+            self.cont(sig)?;
+
+            status = waitpid(self.info.pid, None)?;
+            match status {
+                WaitStatus::PtraceEvent(pid, sig, ev)
+                    if ev == ptrace::Event::PTRACE_EVENT_EXEC as i32 =>
+                {
+                    // 対象のプログラム自身がexecした場合は通常のSIGTRAPとして停止させる
+                    self.setoptions(ptrace::Options::empty())?;
+                    return Ok(WaitStatus::Stopped(pid, sig));
                 }
-                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
-                    Err("子プロセスの実行に失敗しました".into())
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => return Ok(status),
+                _ => (),
+            }
+        }
+    }
+
+    /// 生成した子プロセスの最初の停止を処理
+    /// stateはfork直後に作成した子プロセスの状態で､エラーの場合は破棄時に子プロセスを回収する
+    /// pipe_rはexecに失敗した子プロセスがエラーメッセージを書き込むパイプで､
+    /// 子プロセスが終了した場合のみ読み込む
+    /// exec前にシグナルで停止した場合は書き込み側がまだ閉じられておらず､読み込むと戻らないため
+    fn on_spawned(
+        mut self,
+        state: Running,
+        status: WaitStatus,
+        pipe_r: i32,
+    ) -> Result<ZDbg<Running>, DynError> {
+        let child = state.pid;
+        match status {
+            WaitStatus::Stopped(..) => {
+                println!("<<子プロセスの実行に成功しました : PID = {child}>>");
+                self.info.brk_stopped = false;
+                self.info.brk_inserted = false;
+                // 前回のプロセスのレジスタと比較しないよう破棄
+                self.info.stop_regs = None;
+                self.info.prev_regs = None;
+                // 初期スタックを後から参照できるようexec直後のrspを保存
+                self.info.entry_sp = Some(self.getregs()?.rsp);
+                Ok(ZDbg::<Running> {
+                    info: self.info,
+                    _state: state,
+                })
+            }
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                state.disarm(); // waitpidで回収済み
+                match read_first_line(pipe_r) {
+                    Some(msg) => Err(format!("子プロセスの実行に失敗しました : {msg}").into()),
+                    None => Err("子プロセスの実行に失敗しました".into()),
                 }
-                _ => Err("子プロセスが不正な状態です".into()),
-            },
+            }
+            _ => Err("子プロセスが不正な状態です".into()),
         }
     }
 }
 
 /// Running時に呼び出し可能なメソッド
 impl ZDbg<Running> {
-    fn do_cmd(self, cmd: &[&str]) -> Result<State, DynError> {
+    pub fn do_cmd(mut self, cmd: &[&str]) -> Result<State, DynError> {
         if cmd.is_empty() {
             return Ok(State::Running(self));
         }
 
         match cmd[0] {
             "break" | "b" => self.do_break(cmd)?,
+            "clear" => self.do_clear(cmd)?,
+            "info" if cmd.get(1) == Some(&"proc") => self.do_info_proc()?,
             "continue" | "c" => return self.do_continue(),
             "registers" | "regs" => {
-                let args = ptrace::getregs(self.info.pid)?;
-                print_regs(&args);
+                let args = self.getregs()?;
+                let all = cmd.get(1) == Some(&"all");
+                print_regs(&args, self.info.prev_regs.as_ref(), self.info.pretty_print_regs, all);
             }
             "stepi" | "s" => return self.do_stepi(),
-            "run" | "r" => eprintln!("<<すでに実行中です>>"),
+            "telescope" | "tel" => self.do_telescope(cmd),
+            "context" | "ctx" => self.do_context()?,
+            "vmmap" => self.do_vmmap(cmd)?,
+            "patch" => self.do_patch(cmd)?,
+            "nop" => self.do_nop(cmd)?,
+            "argv" => self.do_argv(),
+            "auxv" => self.do_auxv(),
+            "retaddr" => self.do_retaddr(cmd)?,
+            "regsnap" => self.do_regsnap(cmd)?,
+            "regdiff" => self.do_regdiff(cmd)?,
+            "save" => self.do_save(cmd)?,
+            "diff-registers" => self.do_diff_registers(cmd)?,
+            "run" | "r" | "starti" => eprintln!("<<すでに実行中です>>"),
             "exit" => {
+                if self.info.confirm_kill && !confirm("<<実行中のプロセスをkillして終了しますか? (y/n)>>") {
+                    return Ok(State::Running(self));
+                }
                 self.do_exit()?;
                 return Ok(State::Exit);
             }
@@ -163,106 +805,1837 @@ impl ZDbg<Running> {
         Ok(State::Running(self))
     }
 
-    fn do_break(self, cmd: &[&str]) -> Result<(), DynError> {
+    fn do_break(&mut self, cmd: &[&str]) -> Result<(), DynError> {
         if self.set_break_addr(cmd) {
-            self.set_break()>?;
+            self.set_break()?;
+
+            // 停止中のアドレスに設定した場合､再開した途端にヒットしないよう通過させる
+            let rip = self.getregs()?.rip;
+            self.info.brk_stopped = self.info.brk_addr == Some(rip as *mut c_void);
         }
         Ok(())
     }
 
-    /// continueを実行
-    fn do_continue(self) -> Result<State, DynError> {
-        // ブレークポイントで停止していた場合は1ステップ実行後再設定
-        match self.step_and_break()? {
-            State::Running(r) => {
-                // 実行再開
-                ptrace::cont(r.info.pid, None);
-                r.wait_child()
+    /// contextを実行
+    /// レジスタ､rip周辺のコード､スタックをまとめて表示
+    /// 表示するセクションとその順番はset context-sectionsで変更可能
+    fn do_context(&self) -> Result<(), DynError> {
+        let regs = self.getregs()?;
+
+        for section in self.info.context_sections.iter() {
+            println!("<<{section}>>");
+            match section.as_str() {
+                "regs" => {
+                    print_regs(&regs, self.info.prev_regs.as_ref(), self.info.pretty_print_regs, false)
+                }
+                "code" => {
+                    // 逆アセンブラがないため､rip以降のバイト列を表示
+                    match self.read_bytes(regs.rip, 16) {
+                        Ok(code) => {
+                            print_bytes(regs.rip, &code);
+                            println!();
+                        }
+                        Err(e) => eprintln!("<<ptrace::readに失敗 : {e}, addr = {:#x}>>", regs.rip),
+                    }
+                }
+                "stack" => self.telescope(regs.rsp, 8),
+                _ => unreachable!(),
             }
-            n => Ok(n)
         }
-    }
 
-    fn do_stepi(self) -> Result<State, DynError> {}
+        Ok(())
+    }
 
-    fn do_exit(self) -> Result<(), DynError> {
-        loop {
-            ptrace::kill(self.info.pid)?;
-            match waitpid(self.info.pid, None)? {
-                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => return Ok(()),
-                _ => (),
-            }
+    /// patchを実行
+    /// 命令をアセンブルし指定アドレスに書き込む
+    fn do_patch(&mut self, cmd: &[&str]) -> Result<(), DynError> {
+        if cmd.len() < 3 {
+            eprintln!("<<引数が足りません : patch <addr> <命令>>>");
+            return Ok(());
         }
+
+        let addr = match parse_num(cmd[1]) {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+
+        let code = match assemble(addr, &cmd[2..]) {
+            Some(code) => code,
+            None => return Ok(()),
+        };
+
+        self.patch_bytes(addr, &code)
     }
 
-    /// ブレークポイントを実際に設定
-    /// つまり､該当アドレスのメモリを"int 3" = 0xccに設定
-    fn set_break(&mut self) -> Result<(), DynError> {
-        let addr = if let Some(addr) = self.info.brk_addr {
-            addr
-        } else {
+    /// nopを実行
+    /// 指定アドレスからcountバイトをnop(0x90)で書き換える
+    /// 逆アセンブラがないため､countの省略時は1バイトとする
+    fn do_nop(&mut self, cmd: &[&str]) -> Result<(), DynError> {
+        // 逆アセンブラがなく命令長を求められないため､countは省略できない
+        // 既定値を1バイトにすると多くの命令の途中までを書き換えてしまう
+        if cmd.len() < 3 {
+            eprintln!("<<アドレスとバイト数を指定してください : nop <addr> <count>>>");
             return Ok(());
+        }
+
+        let addr = match parse_num(cmd[1]) {
+            Some(addr) => addr,
+            None => return Ok(()),
         };
 
-        // ブレークするアドレスにあるメモリ上の値を取得
-        let val = match ptrace::read(self.info.pid, addr as *mut c_char) {
-            Ok(val) => val,
+        let count = match parse_num(cmd[2]) {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+        if count > NOP_MAX {
+            eprintln!("<<バイト数が大きすぎます : {count}, 最大 : {NOP_MAX}>>");
+            return Ok(());
+        }
+
+        self.patch_bytes(addr, &vec![0x90; count as usize])
+    }
+
+    /// addrからのメモリをcodeで書き換え､前後の値を表示
+    fn patch_bytes(&mut self, addr: u64, code: &[u8]) -> Result<(), DynError> {
+        warn_if_non_canonical(addr);
+        self.warn_if_readonly(addr, code.len() as u64)?;
+
+        // 書き込めないアドレスを指定した場合もデバッガは終了させない
+        let before = match self.read_bytes(addr, code.len()) {
+            Ok(before) => before,
             Err(e) => {
-                eprintln!("<<ptrace::readに失敗 : {e}, addr = {:p}>>", addr);
+                eprintln!("<<ptrace::readに失敗 : {e}, addr = {:#x}>>", addr);
                 return Ok(());
             }
         };
-
-        // メモリ上の値を表示する補助関数
-        fn print_val(addr: usize, val: i64) {
-            print!("{:x}:", addr);
-            for n in (0..8).map(|n| ((val >> (n * 8)) & 0xff) as u8) {
-                print!(" {:x}", n);
-            }
+        if let Err(e) = self.write_bytes(addr, code) {
+            eprintln!("<<ptrace::writeに失敗 : {e}, addr = {:#x}>>", addr);
+            return Ok(());
         }
+        let after = self.read_bytes(addr, code.len())?;
 
-        println!("<<以下のようにメモリを書き換えます>>");
-        print!("<<before: "); // 元の値を表示
-        print_val(addr as usize, val.into());
+        println!("<<以下のようにメモリを書き換えました>>");
+        print!("<<before: ");
+        print_bytes(addr, &before);
         println!(">>");
-
-        let val_int3 = (val & !0xff) | 0xcc; // "int 3"に設定
-        print!("<<after: "); // 変更後の値を表示
-        print_val(addr as usize, val.into());
+        print!("<<after: ");
+        print_bytes(addr, &after);
         println!(">>");
 
-        // "int 3"をメモリに書き込み
-        match unsafe {
-            ptrace::write(self.info.pid, addr as *mut c_char, val_int3)
-        } {
-            Ok(_)   => {
-                self.info.brk_addr = Some(addr);
-                self.info.brk_val = val as i64; // 元の値を保存
-            }
-            Err(e) => {
-                eprintln!("<<ptrace::writeに失敗 : {e}, addr = {:p}>>", addr);
-            }
+        Ok(())
+    }
+
+    /// 書き込み先が書き込み不可の領域であれば警告し､trueを返す
+    /// ptraceはコード領域などにも書き込めるため､エラーにはしない
+    fn warn_if_readonly(&self, addr: u64, len: u64) -> Result<bool, DynError> {
+        let entries = maps::read_maps(self.info.pid)?;
+        let end = addr.saturating_add(len);
+
+        let readonly = entries
+            .iter()
+            .filter(|e| e.start < end && addr < e.end)
+            .any(|e| !e.perms.contains('w'));
+        if readonly {
+            eprintln!("<<書き込み先は読み取り専用領域です>>");
         }
 
-        Ok(())
+        Ok(readonly)
     }
 
-    /// ブレークポイントで停止していた場合は
-    /// 1ステップ実行しブレークポイントを再設定
-    fn step_and_break(mut self) -> Result<State, DynError> {
-        let regs = getregs(self.info.pid)?;
-        if Some((regs.rip) as *mut c_void) == self.info.brk_addr {
-            ptrace::step(self.info.pid, None)?; // 1ステップ実行
-            match waitpid(self.info.pid, None)? {
-                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
-                    println!("<<子プロセスが終了>>");
-                    return Ok(State::NotRunning(ZDbg::<NotRunning> {info: self.info, _state: NotRunning}));
-                }
-                _=>(),
-            }
-            self.set_break()?;
+    /// addrからlenバイト読み込む
+    fn read_bytes(&self, addr: u64, len: usize) -> Result<Vec<u8>, DynError> {
+        if len > READ_MAX {
+            return Err(format!("サイズが大きすぎます : {len}, 最大 : {READ_MAX}").into());
+        }
+        let end = addr
+            .checked_add(len as u64)
+            .ok_or("範囲がアドレス空間の終端を超えています")?;
+
+        let mut result = Vec::with_capacity(len);
+        for a in (addr..end).step_by(8) {
+            let val = ptrace::read(self.info.pid, a as *mut c_void)?;
+            result.extend_from_slice(&val.to_le_bytes());
+        }
+        result.truncate(len);
+        Ok(result)
+    }
+
+    /// addrからbytesを書き込む
+    /// 書き込み範囲外のバイトは元の値を保持する
+    /// ブレークポイントと重なる場合は"int 3"を残したまま､元の値として保存している側を書き換える
+    fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), DynError> {
+        let mut bytes = bytes.to_vec();
+
+        if let Some(brk) = self.info.brk_addr.map(|a| a as u64) {
+            let mut brk_bytes = self.info.brk_val.to_le_bytes();
+            for (i, b) in bytes.iter_mut().enumerate() {
+                let a = addr
+                    .checked_add(i as u64)
+                    .ok_or("範囲がアドレス空間の終端を超えています")?;
+                // ブレークポイントのアドレスがアドレス空間の終端付近でもあふれないよう差で判定
+                let n = a.wrapping_sub(brk) as usize;
+                if n >= 8 {
+                    continue;
+                }
+
+                let inserted = n == 0 && self.info.brk_inserted;
+                brk_bytes[n] = *b;
+                if inserted {
+                    eprintln!("<<ブレークポイントを上書きします : Addr = {:#x}>>", brk);
+                    *b = 0xcc;
+                }
+            }
+            self.info.brk_val = i64::from_le_bytes(brk_bytes);
+        }
+
+        for (k, chunk) in bytes.chunks(8).enumerate() {
+            let a = addr
+                .checked_add(k as u64 * 8)
+                .ok_or("範囲がアドレス空間の終端を超えています")?;
+            let mut word = ptrace::read(self.info.pid, a as *mut c_void)?.to_le_bytes();
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.poke(a, i64::from_le_bytes(word))?;
+        }
+
+        Ok(())
+    }
+
+    /// argvを実行
+    /// 初期スタックからargcとargv､envpを表示
+    /// 読み込めない文字列は<invalid>と表示する
+    fn do_argv(&self) {
+        let stack = match self.read_initial_stack() {
+            Some(stack) => stack,
+            None => return,
+        };
+
+        if let Some(argc) = stack.argc {
+            println!("argc = {argc}");
+        }
+        for (i, p) in stack.argv.iter().enumerate() {
+            println!("argv[{i}] = {:#x}: {}", p, self.read_cstr_or_invalid(*p));
+        }
+        for (i, p) in stack.envp.iter().enumerate() {
+            println!("envp[{i}] = {:#x}: {}", p, self.read_cstr_or_invalid(*p));
+        }
+    }
+
+    /// auxvを実行
+    /// 初期スタックから補助ベクタを表示
+    fn do_auxv(&self) {
+        let stack = match self.read_initial_stack() {
+            Some(stack) => stack,
+            None => return,
+        };
+
+        for (key, val) in stack.auxv {
+            match auxv_name(key) {
+                Some(name @ ("AT_PLATFORM" | "AT_BASE_PLATFORM" | "AT_EXECFN")) => {
+                    println!("{:<18} {:#x}: {}", name, val, self.read_cstr_or_invalid(val))
+                }
+                Some(name) => println!("{:<18} {:#x}", name, val),
+                None => println!("{:<18} {:#x}", key, val),
+            }
+        }
+    }
+
+    /// exec直後のrspから初期スタックを読み込む
+    /// 文字列や配列自体はmainの実行後も残っていることが多いが､
+    /// プログラムが書き換えている可能性があるためexec直後以外は参考程度
+    fn read_initial_stack(&self) -> Option<InitialStack> {
+        let sp = match self.info.entry_sp {
+            Some(sp) => sp,
+            None => {
+                eprintln!("<<初期スタックの位置が不明です>>");
+                return None;
+            }
+        };
+
+        let mut stack = InitialStack {
+            argc: None,
+            argv: Vec::new(),
+            envp: Vec::new(),
+            auxv: Vec::new(),
+        };
+        self.fill_initial_stack(sp, &mut stack);
+        Some(stack)
+    }
+
+    /// spから初期スタックを順に読み込んでstackに追加
+    /// 読み込めないワードがあった場合はその旨を表示してNoneを返し､
+    /// stackにはそこまでに読み込めた分が残る
+    fn fill_initial_stack(&self, sp: u64, stack: &mut InitialStack) -> Option<()> {
+        let read = |addr: u64| match ptrace::read(self.info.pid, addr as *mut c_void) {
+            Ok(val) => Some(val as u64),
+            Err(e) => {
+                eprintln!("<<ptrace::readに失敗 : {e}, addr = {:#x}>>", addr);
+                None
+            }
+        };
+
+        let argc = read(sp)?;
+        stack.argc = Some(argc);
+        let mut addr = sp.wrapping_add(8);
+
+        // argcが書き換えられていても読み込めなくなった時点で打ち切られる
+        for _ in 0..argc {
+            stack.argv.push(read(addr)?);
+            addr = addr.wrapping_add(8);
+        }
+        addr = addr.wrapping_add(8); // argvの終端のNULL
+
+        loop {
+            let p = read(addr)?;
+            addr = addr.wrapping_add(8);
+            if p == 0 {
+                break;
+            }
+            stack.envp.push(p);
+        }
+
+        loop {
+            let key = read(addr)?;
+            let val = read(addr.wrapping_add(8))?;
+            addr = addr.wrapping_add(16);
+            if key == 0 {
+                break; // AT_NULL
+            }
+            stack.auxv.push((key, val));
+        }
+
+        Some(())
+    }
+
+    /// read_cstrと同様だが､読み込めない場合は<invalid>を返す
+    fn read_cstr_or_invalid(&self, addr: u64) -> String {
+        self.read_cstr(addr).unwrap_or_else(|_| "<invalid>".to_string())
+    }
+
+    /// addrからNUL終端された文字列を読み込み､set charsetに従って表示用の文字列に変換
+    fn read_cstr(&self, addr: u64) -> Result<String, DynError> {
+        let bytes = self.read_cstr_bytes(addr)?;
+        Ok(decode_str(&bytes, self.info.charset))
+    }
+
+    /// addrにNUL終端された表示可能なASCII文字列があれば､表示用の文字列に変換して返す
+    /// telescopeでポインタの先が文字列かを判定するために使う
+    fn read_printable(&self, addr: u64) -> Option<String> {
+        let bytes = self.read_cstr_bytes(addr).ok()?;
+        if bytes.is_empty() || !bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            return None;
+        }
+        Some(decode_str(&bytes, self.info.charset))
+    }
+
+    /// addrからNUL終端までのバイト列を読み込む
+    /// 終端が見つからない場合は4096バイトで打ち切る
+    fn read_cstr_bytes(&self, addr: u64) -> Result<Vec<u8>, DynError> {
+        let mut bytes = Vec::new();
+        'outer: for a in (addr..addr.saturating_add(4096)).step_by(8) {
+            let val = ptrace::read(self.info.pid, a as *mut c_void)?;
+            for b in val.to_le_bytes() {
+                if b == 0 {
+                    break 'outer;
+                }
+                bytes.push(b);
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// retaddrを実行
+    /// rspからnワード分のスタックを走査し､実行可能領域内かつ直前がcall命令である値を
+    /// 戻りアドレスとして表示する
+    /// フレームポインタを使わないバイナリでも大まかな呼び出し履歴が得られる
+    fn do_retaddr(&self, cmd: &[&str]) -> Result<(), DynError> {
+        let n = if cmd.len() > 1 {
+            match parse_num(cmd[1]) {
+                Some(n) => n,
+                None => return Ok(()),
+            }
+        } else {
+            RETADDR_DEPTH
+        };
+
+        let entries = maps::read_maps(self.info.pid)?;
+        let exec: Vec<_> = entries.iter().filter(|e| e.perms.contains('x')).collect();
+        let rsp = self.getregs()?.rsp;
+
+        for i in 0..n {
+            let addr = rsp + i * 8;
+            let val = match ptrace::read(self.info.pid, addr as *mut c_void) {
+                Ok(val) => val as u64,
+                Err(_) => break, // スタックの終端
+            };
+
+            let e = match exec.iter().find(|e| e.contains(val)) {
+                Some(e) => e,
+                None => continue,
+            };
+
+            // 戻りアドレスの直前8バイトを読みcall命令かを確認
+            if val - e.start < 8 {
+                continue;
+            }
+            let code = self.read_bytes(val - 8, 8)?;
+            if !is_after_call(&code) {
+                continue;
+            }
+
+            println!(
+                "{:#x} (rsp+{:#x}): {:#x} {}+{:#x}",
+                addr,
+                i * 8,
+                val,
+                e.path,
+                val - e.start + e.offset
+            );
+        }
+
+        Ok(())
+    }
+
+    /// regsnapを実行
+    /// 現在のレジスタを名前を付けて保存
+    fn do_regsnap(&mut self, cmd: &[&str]) -> Result<(), DynError> {
+        if cmd.len() < 2 {
+            eprintln!("<<スナップショット名を指定してください : regsnap <名前>>>");
+            return Ok(());
+        }
+
+        let regs = self.getregs()?;
+        self.info.reg_snapshots.insert(cmd[1].to_string(), regs);
+        println!("<<レジスタを保存しました : {}>>", cmd[1]);
+        Ok(())
+    }
+
+    /// regdiffを実行
+    /// 保存したスナップショットと現在のレジスタを比較し､異なるものだけを表示
+    fn do_regdiff(&self, cmd: &[&str]) -> Result<(), DynError> {
+        if cmd.len() < 2 {
+            eprintln!("<<スナップショット名を指定してください : regdiff <名前>>>");
+            return Ok(());
+        }
+
+        let old = match self.info.reg_snapshots.get(cmd[1]) {
+            Some(old) => old,
+            None => {
+                let mut names: Vec<_> = self.info.reg_snapshots.keys().map(|k| k.as_str()).collect();
+                names.sort();
+                eprintln!(
+                    "<<スナップショットがありません : {}, 保存済み : {}>>",
+                    cmd[1],
+                    names.join(" ")
+                );
+                return Ok(());
+            }
+        };
+
+        let regs = self.getregs()?;
+        print_regs_diff(&regs_list(old, true), &regs);
+        Ok(())
+    }
+
+    /// saveを実行
+    /// save registers <ファイル> : 全レジスタを1行に1つ "名前 0x値" の形式で保存
+    /// save memory <addr> <len> <ファイル> : addrからlenバイトをそのままバイナリで保存
+    fn do_save(&self, cmd: &[&str]) -> Result<(), DynError> {
+        match cmd.get(1) {
+            Some(&"registers") if cmd.len() == 3 => {
+                let regs = self.getregs()?;
+                let text: String = regs_list(&regs, true)
+                    .into_iter()
+                    .map(|(name, val)| format!("{name} {val:#x}\n"))
+                    .collect();
+                if let Err(e) = fs::write(cmd[2], text) {
+                    eprintln!("<<ファイルに書き込めません : {}, {e}>>", cmd[2]);
+                    return Ok(());
+                }
+                println!("<<レジスタを保存しました : {}>>", cmd[2]);
+            }
+            Some(&"memory") if cmd.len() == 5 => {
+                let (addr, len) = match (parse_num(cmd[2]), parse_num(cmd[3])) {
+                    (Some(addr), Some(len)) => (addr, len as usize),
+                    _ => return Ok(()),
+                };
+                let bytes = match self.read_bytes(addr, len) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        eprintln!("<<メモリを読み込めません : {e}, addr = {:#x}>>", addr);
+                        return Ok(());
+                    }
+                };
+                if let Err(e) = fs::write(cmd[4], bytes) {
+                    eprintln!("<<ファイルに書き込めません : {}, {e}>>", cmd[4]);
+                    return Ok(());
+                }
+                println!("<<{:#x}から{}バイトを保存しました : {}>>", addr, len, cmd[4]);
+            }
+            _ => eprintln!(
+                "<<引数が不正です : save registers <ファイル> | save memory <addr> <len> <ファイル>>>"
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// diff-registersを実行
+    /// save registersで保存したファイルと現在のレジスタを比較
+    fn do_diff_registers(&self, cmd: &[&str]) -> Result<(), DynError> {
+        if cmd.len() < 2 {
+            eprintln!("<<ファイルを指定してください : diff-registers <ファイル>>>");
+            return Ok(());
+        }
+
+        let text = match fs::read_to_string(cmd[1]) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("<<ファイルを読み込めません : {}, {e}>>", cmd[1]);
+                return Ok(());
+            }
+        };
+        let mut old = Vec::new();
+        for line in text.lines() {
+            match parse_reg_line(line) {
+                Some(pair) => old.push(pair),
+                None => {
+                    eprintln!("<<レジスタファイルの形式が不正です : {line}>>");
+                    return Ok(());
+                }
+            }
+        }
+
+        let regs = self.getregs()?;
+        print_regs_diff(&old, &regs);
+        Ok(())
+    }
+
+    /// info procを実行
+    /// /proc/<pid>/statusと/proc/<pid>/taskから状態､スレッド､子プロセス､シグナルマスクを表示
+    fn do_info_proc(&self) -> Result<(), DynError> {
+        let status = procfs::read_status(self.info.pid)?;
+        let get = |key: &str| {
+            status
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .unwrap_or("-")
+        };
+
+        for key in ["Name", "State", "Pid", "PPid", "TracerPid", "VmRSS"] {
+            println!("{:<12} {}", key, get(key));
+        }
+
+        let tids = procfs::read_tasks(self.info.pid)?;
+        println!("{:<12} {} {:?}", "Threads", tids.len(), tids);
+
+        let children = procfs::read_children(self.info.pid, &tids);
+        println!("{:<12} {} {:?}", "Children", children.len(), children);
+
+        for key in ["SigPnd", "ShdPnd", "SigBlk", "SigIgn", "SigCgt"] {
+            println!("{:<12} {}", key, get(key));
+        }
+
+        // zdbgはfork時の子プロセスしか追跡していない
+        if tids.len() > 1 {
+            eprintln!("<<マルチスレッドのプロセスですが､追跡しているのはPID = {}のみです>>", self.info.pid);
+        }
+        if !children.is_empty() {
+            eprintln!("<<子プロセスは追跡していません>>");
+        }
+
+        Ok(())
+    }
+
+    /// vmmapを実行
+    /// 引数がない場合はメモリマップ全体を表示し､
+    /// アドレスを指定した場合はそのアドレスを含む領域とオフセットを表示
+    fn do_vmmap(&self, cmd: &[&str]) -> Result<(), DynError> {
+        let entries = maps::read_maps(self.info.pid)?;
+
+        if cmd.len() < 2 {
+            for e in entries.iter() {
+                println!("{}", e.to_line());
+            }
+            return Ok(());
+        }
+
+        let addr = match parse_num(cmd[1]) {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+
+        match entries.iter().find(|e| e.contains(addr)) {
+            Some(e) => {
+                println!("{}", e.to_line());
+                println!("<<{:#x} = {:#x} + {:#x}>>", addr, e.start, addr - e.start);
+            }
+            None => eprintln!("<<{:#x}を含む領域はありません>>", addr),
+        }
+
+        Ok(())
+    }
+
+    /// telescopeを実行
+    /// 指定アドレスから1ワードずつ読み込み､値がポインタであればその先をたどって表示
+    fn do_telescope(&self, cmd: &[&str]) {
+        if cmd.len() < 2 {
+            eprintln!("<<アドレスを指定してください : telescope <addr> [n]>>");
+            return;
+        }
+
+        let addr = match parse_num(cmd[1]) {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        let n = if cmd.len() > 2 {
+            match parse_num(cmd[2]) {
+                Some(n) => n,
+                None => return,
+            }
+        } else {
+            8
+        };
+
+        self.telescope(addr, n);
+    }
+
+    /// addrからnワード分を､ポインタをたどりながら表示
+    fn telescope(&self, addr: u64, n: u64) {
+        for i in 0..n {
+            let offset = i * 8;
+            let cur = addr.wrapping_add(offset);
+            let val = match ptrace::read(self.info.pid, cur as *mut c_void) {
+                Ok(val) => val as u64,
+                Err(e) => {
+                    eprintln!("<<ptrace::readに失敗 : {e}, addr = {:#x}>>", cur);
+                    return;
+                }
+            };
+
+            print!("{:02}:{:04x}| {:#x}: {:#x}", i, offset, cur, val);
+
+            // 読み込めるかぎりポインタをたどる
+            // 循環を避けるため既に訪れたアドレスと深さで打ち切る
+            let mut visited = vec![cur];
+            let mut ptr = val;
+            let mut looped = false;
+            for _ in 0..TELESCOPE_DEPTH {
+                if visited.contains(&ptr) {
+                    print!(" -> [loop]");
+                    looped = true;
+                    break;
+                }
+
+                match ptrace::read(self.info.pid, ptr as *mut c_void) {
+                    Ok(next) => {
+                        print!(" -> {:#x}", next as u64);
+                        visited.push(ptr);
+                        ptr = next as u64;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            // 最後にたどったポインタの先が文字列であれば併せて表示
+            if visited.len() > 1 && !looped {
+                if let Some(text) = self.read_printable(visited[visited.len() - 1]) {
+                    print!(" {text}");
+                }
+            }
+            println!();
+        }
+    }
+
+    /// clearを実行
+    /// 指定アドレス (省略時は現在のrip) のブレークポイントを削除し､
+    /// メモリ上の"int 3"を元の値に戻す
+    fn do_clear(&mut self, cmd: &[&str]) -> Result<(), DynError> {
+        let addr = if cmd.len() < 2 {
+            self.getregs()?.rip
+        } else {
+            match parse_num(cmd[1]) {
+                Some(addr) => addr,
+                None => return Ok(()),
+            }
+        };
+
+        // 書き込みに失敗した場合や停止中に元の値に戻している場合は"int 3"はメモリにない
+        let inserted = self.info.brk_inserted;
+        let n = self.clear_break_addr(addr);
+        if n > 0 && inserted {
+            // 先頭1バイトのみを元に戻す
+            let orig = self.info.brk_val.to_le_bytes()[0];
+            if let Err(e) = self.write_bytes(addr, &[orig]) {
+                eprintln!("<<ptrace::writeに失敗 : {e}, addr = {:#x}>>", addr);
+            }
+        }
+        println!("<<{n}個のブレークポイントを削除しました>>");
+
+        Ok(())
+    }
+
+    /// continueを実行
+    fn do_continue(self) -> Result<State, DynError> {
+        // ブレークポイントで停止していた場合は1ステップ実行後再設定
+        match self.step_and_break()? {
+            State::Running(r) => {
+                // 実行再開
+                r.cont(None)?;
+                r.wait_child()
+            }
+            n => Ok(n)
+        }
+    }
+
+    /// stepiを実行
+    fn do_stepi(self) -> Result<State, DynError> {
+        if self.info.brk_stopped {
+            // 次の実行先がブレークポイントの場合､
+            // 元の値に戻してから実行し再設定する
+            // "int 3"は実行されないためヒットとは扱わず､通常のステップと同様に停止を通知
+            match self.step_and_break()? {
+                State::Running(r) => {
+                    let regs = r.getregs()?;
+                    r.on_stop(regs)
+                }
+                s => Ok(s),
+            }
+        } else {
+            self.step()?;
+            self.wait_child()
+        }
+    }
+
+    /// 子プロセスの停止を待機
+    /// ブレークポイントで停止した場合､ripは"int 3"の次を指しているため
+    /// メモリを元の値に戻した上でripを1つ戻す
+    fn wait_child(mut self) -> Result<State, DynError> {
+        match waitpid(self.info.pid, None)? {
+            WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                println!("<<子プロセスが終了しました>>");
+                Ok(State::NotRunning(self.into_not_running()))
+            }
+            WaitStatus::Stopped(..) => {
+                let mut regs = self.getregs()?;
+                if self.is_brk_trap()?
+                    && Some((regs.rip - 1) as *mut c_void) == self.info.brk_addr
+                {
+                    // 書き換えたメモリを元の値に戻す
+                    // always-insertedの場合は再開時まで"int 3"を残す
+                    if !self.info.always_inserted {
+                        self.remove_break()?;
+                    }
+
+                    // ブレークポイントのアドレスから実行を再開できるよう1つ戻す
+                    regs.rip -= 1;
+                    self.setregs(regs)?;
+                }
+                self.on_stop(regs)
+            }
+            _ => Err("waitpidの返り値が不正です".into()),
+        }
+    }
+
+    /// ユーザーに見える停止の共通処理
+    /// 停止位置を表示し､レジスタを記録してstop-hookを実行
+    /// シングルステップでブレークポイントのアドレスに到達した場合も
+    /// ブレークポイントは挿入されたまま残る
+    fn on_stop(mut self, regs: user_regs_struct) -> Result<State, DynError> {
+        println!("<<子プロセスが停止しました : PC = {:#x}>>", regs.rip);
+
+        // 変化したレジスタを表示できるよう停止ごとに記録
+        self.info.prev_regs = self.info.stop_regs.replace(regs);
+        self.info.brk_stopped = self.info.brk_addr == Some(regs.rip as *mut c_void);
+
+        self.run_stop_hook()
+    }
+
+    pub fn do_exit(self) -> Result<(), DynError> {
+        loop {
+            self.kill()?;
+            match waitpid(self.info.pid, None)? {
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    self._state.disarm();
+                    return Ok(());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// 子プロセスが終了した際にNotRunning状態に遷移
+    fn into_not_running(self) -> ZDbg<NotRunning> {
+        self._state.disarm();
+        ZDbg::<NotRunning> {
+            info: self.info,
+            _state: NotRunning,
+        }
+    }
+
+    /// ブレークポイントを実際に設定
+    /// つまり､該当アドレスのメモリを"int 3" = 0xccに設定
+    fn set_break(&mut self) -> Result<(), DynError> {
+        let addr = if let Some(addr) = self.info.brk_addr {
+            addr
+        } else {
+            return Ok(());
+        };
+
+        // ブレークするアドレスにあるメモリ上の値を取得
+        let val = match ptrace::read(self.info.pid, addr) {
+            Ok(val) => val,
+            Err(e) => {
+                eprintln!("<<ptrace::readに失敗 : {e}, addr = {:p}>>", addr);
+                return Ok(());
+            }
+        };
+
+        // メモリ上の値を表示する補助関数
+        fn print_val(addr: usize, val: i64) {
+            print!("{:x}:", addr);
+            for n in (0..8).map(|n| ((val >> (n * 8)) & 0xff) as u8) {
+                print!(" {:x}", n);
+            }
+        }
+
+        println!("<<以下のようにメモリを書き換えます>>");
+        print!("<<before: "); // 元の値を表示
+        print_val(addr as usize, val);
+        println!(">>");
+
+        let val_int3 = (val & !0xff) | 0xcc; // "int 3"に設定
+        print!("<<after: "); // 変更後の値を表示
+        print_val(addr as usize, val_int3);
+        println!(">>");
+
+        // "int 3"をメモリに書き込み
+        match self.poke(addr as u64, val_int3) {
+            Ok(_)   => {
+                self.info.brk_addr = Some(addr);
+                self.info.brk_val = val as i64; // 元の値を保存
+                self.info.brk_inserted = true;
+            }
+            Err(e) => {
+                eprintln!("<<ptrace::writeに失敗 : {e}, addr = {:p}>>", addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// ブレークポイントのアドレスのメモリを元の値に戻す
+    /// "int 3"を書き込んでいない場合は何もしない
+    fn remove_break(&mut self) -> Result<(), DynError> {
+        let addr = match self.info.brk_addr {
+            Some(addr) if self.info.brk_inserted => addr,
+            _ => return Ok(()),
+        };
+
+        // 挿入後に子プロセスが後続のバイトを書き換えている場合があるため､
+        // 現在の値の先頭1バイトのみを元に戻す
+        let mut word = ptrace::read(self.info.pid, addr)?.to_le_bytes();
+        word[0] = self.info.brk_val as u8;
+        self.poke(addr as u64, i64::from_le_bytes(word))?;
+        self.info.brk_inserted = false;
+        Ok(())
+    }
+
+    /// 停止時にstop-hookのコマンドを実行
+    /// 実行を再開するコマンドは設定時に除外しているため､ここから停止処理は再帰しない
+    fn run_stop_hook(self) -> Result<State, DynError> {
+        let hooks = self.info.stop_hook.clone();
+        let mut dbg = self;
+        for hook in hooks.iter() {
+            let cmd: Vec<&str> = hook.split(' ').collect();
+            match dbg.do_cmd(&cmd)? {
+                State::Running(r) => dbg = r,
+                s => return Ok(s),
+            }
+        }
+
+        Ok(State::Running(dbg))
+    }
+
+    /// 直前の停止が"int 3"によるSIGTRAPかを判定
+    /// シングルステップによるSIGTRAPの場合はripを戻してはならないため､
+    /// si_codeで両者を区別する
+    fn is_brk_trap(&self) -> Result<bool, DynError> {
+        let info = self.getsiginfo()?;
+        if info.si_signo != Signal::SIGTRAP as i32 {
+            return Ok(false);
+        }
+
+        // シングルステップの場合はTRAP_TRACEとなる
+        Ok(matches!(info.si_code, SI_KERNEL | TRAP_BRKPT))
+    }
+
+    /// ブレークポイントで停止していた場合は
+    /// 1ステップ実行しブレークポイントを再設定
+    /// ripとブレークポイントのアドレスの比較ではなくbrk_stoppedで判定するため､
+    /// exec直後のエントリポイントに設定したブレークポイントは通過せずにヒットする
+    fn step_and_break(mut self) -> Result<State, DynError> {
+        if self.info.brk_stopped {
+            self.info.brk_stopped = false;
+            self.remove_break()?; // 停止中も挿入したままの場合に備えて元の値に戻す
+            self.step()?; // 1ステップ実行
+            match waitpid(self.info.pid, None)? {
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    println!("<<子プロセスが終了>>");
+                    return Ok(State::NotRunning(self.into_not_running()));
+                }
+                _=>(),
+            }
+            self.set_break()?;
         }
 
         Ok(State::Running(self))
     }
 }
+
+/// ヘルプを表示
+fn do_help() {
+    println!(
+        r#"コマンド一覧 (括弧内は省略記法)
+break 0x8000            : ブレークポイントを0x8000番地に設定 (b 0x8000)
+clear [0x8000]          : 0x8000番地 (省略時は現在のrip) のブレークポイントを削除
+run                     : プログラムを実行 (r)
+starti                  : プログラムを実行し最初の命令で停止
+continue                : プログラムを再開 (c)
+stepi                   : 機械語レベルで1ステップ実行 (s)
+registers [all]         : レジスタを表示 (regs)
+                          allの場合はセグメントレジスタとfs_base, gs_baseも表示
+context                 : レジスタ､コード､スタックをまとめて表示 (ctx)
+telescope 0x8000 [n]    : 0x8000番地からnワードをポインタをたどって表示 (tel)
+vmmap [0x8000]          : メモリマップを表示
+patch 0x8000 <命令>     : 0x8000番地を命令で書き換え
+nop 0x8000 n            : 0x8000番地からnバイトをnopで書き換え (命令長は確認しない)
+regsnap <名前>          : 現在のレジスタを名前を付けて保存
+regdiff <名前>          : 保存したレジスタから変化したものを表示
+save registers <file>   : 全レジスタをファイルに保存
+save memory 0x8000 <len> <file> : 0x8000番地からlenバイトをファイルに保存
+diff-registers <file>   : 保存したレジスタファイルから変化したものを表示
+retaddr [n]             : スタックからnワード分の戻りアドレスらしき値を探して表示
+argv                    : exec直後のスタックからargc, argv, envpを表示
+auxv                    : exec直後のスタックから補助ベクタを表示
+info hooks              : stop-hookの一覧を表示
+info proc               : 子プロセスの状態､スレッド､シグナルマスクを表示
+set <設定名> <値>       : 設定を変更
+                          pretty-print-regs on|off, confirm-kill on|off, verbose on|off,
+                          maintenance-time on|off,
+                          context-sections <regs|code|stack>...,
+                          inferior-rlimit <core|cpu|data|fsize|nofile|stack|as> <値|unlimited>,
+                          osabi auto|linux, charset ascii|utf-8|latin1,
+                          breakpoint always-inserted on|off, inferior-tty <パス>|none,
+                          exec-wrapper <コマンド> [引数]...|none,
+                          stop-hook <コマンド> [; <コマンド>]...|none
+exit                    : 終了
+version                 : バージョンとビルド情報を表示
+help                    : このヘルプを表示 (h)"#
+    );
+}
+
+/// バージョンとビルド情報を表示
+/// 不具合報告の際にどの機能が組み込まれているかを確認するためのもの
+fn do_version() {
+    println!("zdbg {}", env!("CARGO_PKG_VERSION"));
+    println!("target     : {}", env!("TARGET"));
+    println!("profile    : {}", if cfg!(debug_assertions) { "debug" } else { "release" });
+
+    // 逆アセンブラ､DWARF､TUI､DAPはいずれも未実装
+    println!("features   : (なし)");
+
+    // リンクしているライブラリのバージョンはbuild.rsがCargo.lockから取得
+    println!("{:<11}: {}", "nix", env!("NIX_VERSION"));
+    println!("{:<11}: {}", "rustyline", env!("RUSTYLINE_VERSION"));
+}
+
+/// breakコマンドの引数からブレークポイントのアドレスを取得
+fn get_break_addr(cmd: &[&str]) -> Option<*mut c_void> {
+    if cmd.len() < 2 {
+        eprintln!("<<アドレスを指定してください\n例 : break 0x8000>>");
+        return None;
+    }
+
+    parse_num(cmd[1]).map(|addr| addr as *mut c_void)
+}
+
+/// 数値をパース
+/// 0xから始まる場合は16進数､それ以外は10進数として扱う
+fn parse_num(s: &str) -> Option<u64> {
+    let result = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u64>()
+    };
+
+    match result {
+        Ok(n) => Some(n),
+        Err(e) => {
+            eprintln!("<<数値の変換に失敗 : {e}, {s}>>");
+            None
+        }
+    }
+}
+
+/// x86-64のカノニカルアドレスかを判定
+/// 上位17ビットがすべて同じ値でないアドレスにアクセスすると即座に一般保護例外となる
+fn is_canonical(addr: u64) -> bool {
+    let upper = addr >> 47;
+    upper == 0 || upper == 0x1ffff
+}
+
+/// カノニカルアドレスでなければ警告
+/// 意図的な場合もあるため書き込み自体は止めない
+fn warn_if_non_canonical(addr: u64) {
+    if !is_canonical(addr) {
+        eprintln!("<<{:#x}はカノニカルアドレスではありません｡アクセスすると一般保護例外となります>>", addr);
+    }
+}
+
+/// バイト列をcharsetに従って表示用の文字列に変換
+/// 変換できないバイトを含む場合は元のバイト列も併せて表示
+fn decode_str(bytes: &[u8], charset: Charset) -> String {
+    let (text, lossy) = match charset {
+        Charset::Ascii => {
+            let text = bytes
+                .iter()
+                .map(|b| if b.is_ascii() { *b as char } else { char::REPLACEMENT_CHARACTER })
+                .collect();
+            (text, !bytes.is_ascii())
+        }
+        Charset::Utf8 => match std::str::from_utf8(bytes) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (String::from_utf8_lossy(bytes).into_owned(), true),
+        },
+        // Latin-1の各バイトはUnicodeの同じ値のコードポイントに対応
+        Charset::Latin1 => (bytes.iter().map(|b| *b as char).collect(), false),
+    };
+
+    if !lossy {
+        return format!("{:?}", text);
+    }
+
+    let raw: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{:?} [raw: {}]", text, raw.join(" "))
+}
+
+/// 戻りアドレスの直前8バイトcodeがcall命令で終わっているかを判定
+/// call rel32 (e8 xx xx xx xx) と､ff /2 の間接callを対象とする
+fn is_after_call(code: &[u8]) -> bool {
+    // call rel32
+    if code[3] == 0xe8 {
+        return true;
+    }
+
+    // call r/m64 : ff のModR/Mのregフィールドが2
+    // レジスタ間接 (2バイト) からSIB+disp32付き (7バイト) までを許容し､
+    // ModR/MとSIBから求めた命令長がちょうど戻りアドレスで終わるもののみとする
+    (2..=7).any(|len| {
+        let i = 8 - len;
+        code[i] == 0xff && (code[i + 1] >> 3) & 7 == 2 && call_rm_len(&code[i + 1..]) == len
+    })
+}
+
+/// ff /2 の命令長
+/// rmはModR/Mから始まるバイト列で､SIBが必要な場合は2バイト目をSIBとして扱う
+fn call_rm_len(rm: &[u8]) -> usize {
+    let modrm = rm[0];
+    let md = modrm >> 6;
+    let base = modrm & 7;
+    if md == 3 {
+        return 2;
+    }
+
+    // ff + ModR/M + SIB
+    let mut len = 2;
+    let mut sib_base = None;
+    if base == 4 {
+        len += 1;
+        sib_base = rm.get(1).map(|sib| sib & 7);
+    }
+
+    len + match md {
+        // [rip + disp32]またはSIBのbaseなし (disp32のみ)
+        0 if base == 5 || sib_base == Some(5) => 4,
+        0 => 0,
+        1 => 1,
+        _ => 4,
+    }
+}
+
+/// 簡易アセンブラ
+/// nop, ret, int3, leaveと､相対アドレスによるjmp, callのみ対応
+fn assemble(addr: u64, asm: &[&str]) -> Option<Vec<u8>> {
+    match asm {
+        ["nop"] => Some(vec![0x90]),
+        ["ret"] => Some(vec![0xc3]),
+        ["int3"] => Some(vec![0xcc]),
+        ["leave"] => Some(vec![0xc9]),
+        ["jmp", target] | ["call", target] => {
+            let target = parse_num(target)?;
+            warn_if_non_canonical(target);
+
+            // rel32は次の命令のアドレスからの相対値
+            let rel = target.wrapping_sub(addr.wrapping_add(5)) as i64;
+            if rel < i32::MIN as i64 || rel > i32::MAX as i64 {
+                eprintln!("<<ジャンプ先が遠すぎます : {:#x}>>", target);
+                return None;
+            }
+
+            let opcode = if asm[0] == "jmp" { 0xe9 } else { 0xe8 };
+            let mut code = vec![opcode];
+            code.extend_from_slice(&(rel as i32).to_le_bytes());
+            Some(code)
+        }
+        _ => {
+            eprintln!("<<対応していない命令です : {}>>", asm.join(" "));
+            None
+        }
+    }
+}
+
+/// メモリ上のバイト列を表示する補助関数
+fn print_bytes(addr: u64, bytes: &[u8]) {
+    print!("{:x}:", addr);
+    for b in bytes {
+        print!(" {:02x}", b);
+    }
+}
+
+/// 端末を開く
+/// 端末でない場合はエラー
+fn open_tty(path: &str) -> Result<i32, DynError> {
+    let fd = open(path, OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty())?;
+    if !isatty(fd).unwrap_or(false) {
+        let _ = close(fd);
+        return Err("端末ではありません".into());
+    }
+    Ok(fd)
+}
+
+/// 新しいセッションを作成し､fdの端末をその制御端末にする
+fn set_controlling_tty(fd: i32) -> nix::Result<()> {
+    setsid()?;
+    Errno::result(unsafe { ioctl(fd, TIOCSCTTY, 0) })?;
+    Ok(())
+}
+
+/// exec前に失敗した子プロセスからエラーメッセージをパイプに書き込んで終了
+/// 送るのはexecまでのエラーのみ
+/// 標準エラー出力をパイプにつなぐとexec後の子プロセスの出力も失われるため
+fn exit_child(pipe_w: i32, msg: &str) -> ! {
+    let _ = write(pipe_w, format!("{msg}\n").as_bytes());
+    // 親プロセスから複製された標準出力のバッファを再度出力しないよう､
+    // 終了処理を行わずに終了
+    unsafe { _exit(1) }
+}
+
+/// パイプから最初の1行を読み込む
+/// 何も書き込まれずに閉じられた場合はNone
+fn read_first_line(fd: i32) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut buf = [0; 256];
+    while let Ok(n) = read(fd, &mut buf) {
+        if n == 0 {
+            break;
+        }
+        bytes.extend_from_slice(&buf[..n]);
+    }
+
+    let text = String::from_utf8_lossy(&bytes);
+    text.lines().next().map(|l| l.to_string())
+}
+
+/// 補助ベクタのキーの名前
+fn auxv_name(key: u64) -> Option<&'static str> {
+    let name = match key {
+        3 => "AT_PHDR",
+        4 => "AT_PHENT",
+        5 => "AT_PHNUM",
+        6 => "AT_PAGESZ",
+        7 => "AT_BASE",
+        8 => "AT_FLAGS",
+        9 => "AT_ENTRY",
+        11 => "AT_UID",
+        12 => "AT_EUID",
+        13 => "AT_GID",
+        14 => "AT_EGID",
+        15 => "AT_PLATFORM",
+        16 => "AT_HWCAP",
+        17 => "AT_CLKTCK",
+        23 => "AT_SECURE",
+        24 => "AT_BASE_PLATFORM",
+        25 => "AT_RANDOM",
+        26 => "AT_HWCAP2",
+        31 => "AT_EXECFN",
+        33 => "AT_SYSINFO_EHDR",
+        51 => "AT_MINSIGSTKSZ",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// 確認メッセージを表示し､yが入力された場合にtrueを返す
+fn confirm(msg: &str) -> bool {
+    print!("{msg} ");
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => matches!(line.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// "on"/"off"を真偽値に変換
+fn parse_on_off(val: &str) -> Option<bool> {
+    match val {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => {
+            eprintln!("<<on か off を指定してください : {val}>>");
+            None
+        }
+    }
+}
+
+/// 表示対象のレジスタ名と値の一覧
+/// allがtrueの場合はセグメントレジスタとFS/GSのベースアドレスも含める
+/// fs_baseはスレッドローカルストレージの位置を示す
+fn regs_list(regs: &user_regs_struct, all: bool) -> Vec<(&'static str, u64)> {
+    let mut list = vec![
+        ("rip", regs.rip),
+        ("rsp", regs.rsp),
+        ("rbp", regs.rbp),
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("eflags", regs.eflags),
+    ];
+
+    if all {
+        list.extend([
+            ("fs_base", regs.fs_base),
+            ("gs_base", regs.gs_base),
+            ("cs", regs.cs),
+            ("ss", regs.ss),
+            ("ds", regs.ds),
+            ("es", regs.es),
+            ("fs", regs.fs),
+            ("gs", regs.gs),
+        ]);
+    }
+
+    list
+}
+
+/// save registersで保存した "名前 0x値" の1行をパース
+fn parse_reg_line(line: &str) -> Option<(&str, u64)> {
+    let (name, val) = line.split_once(' ')?;
+    let val = u64::from_str_radix(val.strip_prefix("0x")?, 16).ok()?;
+    Some((name, val))
+}
+
+/// oldからnewで値が変化したレジスタのみを old -> new の形式で表示
+/// oldは名前と値の組で､newに含まれない名前は無視する
+fn print_regs_diff(old: &[(&str, u64)], new: &user_regs_struct) {
+    let new = regs_list(new, true);
+    let mut changed = false;
+    for (name, o) in old {
+        let n = match new.iter().find(|(n, _)| n == name) {
+            Some((_, n)) => n,
+            None => continue,
+        };
+        if o != n {
+            println!("{:<7} {:#x} -> {:#x}", name, o, n);
+            changed = true;
+        }
+    }
+
+    if !changed {
+        println!("<<変化したレジスタはありません>>");
+    }
+}
+
+/// レジスタを表示
+/// prettyがtrueの場合は1行に4つずつ揃えて表示し､
+/// falseの場合はgrepしやすいよう1行に1つずつ表示
+/// prevが与えられた場合､そこから値が変化したレジスタに*を付ける
+fn print_regs(regs: &user_regs_struct, prev: Option<&user_regs_struct>, pretty: bool, all: bool) {
+    let list = regs_list(regs, all);
+    let changed: Vec<bool> = match prev {
+        Some(prev) => list
+            .iter()
+            .zip(regs_list(prev, all))
+            .map(|((_, val), (_, old))| *val != old)
+            .collect(),
+        None => vec![false; list.len()],
+    };
+
+    if !pretty {
+        for ((name, val), c) in list.into_iter().zip(changed) {
+            let mark = if c { " *" } else { "" };
+            println!("{name} {val:#x}{mark}");
+        }
+        return;
+    }
+
+    for (row, c) in list.chunks(4).zip(changed.chunks(4)) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(c)
+            .map(|((name, val), c)| {
+                let mark = if *c { '*' } else { ' ' };
+                format!("{mark}{:>7}: {:#018x}", name.to_uppercase(), val)
+            })
+            .collect();
+        println!("{}", line.join("  "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::{pty::openpty, sys::wait::WaitPidFlag, unistd::ttyname};
+    use std::{
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::PathBuf,
+        process::Command,
+    };
+
+    /// テスト用の子プロセス
+    /// エントリポイントから以下の命令が並び､.dataのvalに0x1234を書き込んで終了する
+    /// +0x00 nop, +0x01 nop, +0x02 movq $0x1234, val(%rip),
+    /// +0x0d mov $60, %eax, +0x12 xor %edi, %edi, +0x14 syscall
+    const INFERIOR: &str = r#"
+    .globl _start
+    .text
+_start:
+    nop
+    nop
+    movq $0x1234, val(%rip)
+    mov $60, %eax
+    xor %edi, %edi
+    syscall
+    .data
+val:
+    .quad 0
+"#;
+
+    /// asmをlibcに依存しない静的リンクの実行ファイルとしてビルドし､パスを返す
+    /// ldflagsはリンカに追加で渡すオプション
+    fn build(name: &str, asm: &str, ldflags: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zdbg-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let src = dir.join(format!("{name}.S"));
+        let bin = dir.join(name);
+        fs::write(&src, asm).unwrap();
+
+        let status = Command::new("cc")
+            .args(["-nostdlib", "-no-pie", "-o"])
+            .arg(&bin)
+            .arg(&src)
+            .args(ldflags)
+            .status()
+            .expect("ccを実行できません");
+        assert!(status.success(), "{name}のビルドに失敗");
+        bin
+    }
+
+    /// ELFヘッダのe_entryからエントリポイントのアドレスを取得
+    fn entry(path: &PathBuf) -> u64 {
+        let bytes = fs::read(path).unwrap();
+        u64::from_le_bytes(bytes[24..32].try_into().unwrap())
+    }
+
+    /// INFERIORをビルドし､デバッガとエントリポイントのアドレスを返す
+    fn inferior(name: &str) -> (ZDbg<NotRunning>, u64) {
+        let bin = build(name, INFERIOR, &["-static"]);
+        let entry = entry(&bin);
+        (ZDbg::new(bin.to_str().unwrap().to_string()), entry)
+    }
+
+    /// コマンドを実行し､Running状態であることを確認して返す
+    fn running(state: State, cmd: &[&str]) -> ZDbg<Running> {
+        match state.do_cmd(cmd).unwrap() {
+            State::Running(r) => r,
+            _ => panic!("{}の後に子プロセスが停止していません", cmd.join(" ")),
+        }
+    }
+
+    fn rip(dbg: &ZDbg<Running>) -> u64 {
+        ptrace::getregs(dbg.info.pid).unwrap().rip
+    }
+
+    /// .dataの先頭 (INFERIORのval) のアドレス
+    /// .dataは実行ファイルの書き込み可能な領域の先頭にある
+    fn data_addr(dbg: &ZDbg<Running>) -> u64 {
+        maps::read_maps(dbg.info.pid)
+            .unwrap()
+            .into_iter()
+            .find(|e| e.path == dbg.info.filename && e.perms.contains('w'))
+            .unwrap()
+            .start
+    }
+
+    #[test]
+    fn breakpoint_hit_resumes_at_breakpoint_instruction() {
+        let (mut dbg, entry) = inferior("breakpoint_hit_resumes_at_breakpoint_instruction");
+        let movq = entry + 0x02;
+        dbg.do_break(&["break", &format!("{:#x}", movq)]);
+
+        // ripは"int 3"の次ではなくブレークポイントのアドレスに戻り､メモリも元の値に戻る
+        let dbg = running(State::NotRunning(dbg), &["run"]);
+        assert_eq!(rip(&dbg), movq);
+        assert_eq!(dbg.read_bytes(movq, 1).unwrap(), [0x48]);
+
+        // movqが先頭から実行され､valに書き込まれる
+        let data = data_addr(&dbg);
+        assert_eq!(dbg.read_bytes(data, 8).unwrap(), [0; 8]);
+        let dbg = running(State::Running(dbg), &["stepi"]);
+        assert_eq!(rip(&dbg), entry + 0x0d);
+        assert_eq!(dbg.read_bytes(data, 8).unwrap(), 0x1234u64.to_le_bytes());
+
+        // ブレークポイントは再設定されている
+        assert_eq!(dbg.read_bytes(movq, 1).unwrap(), [0xcc]);
+
+        // continueで再開すると最後まで実行して終了する
+        let state = State::Running(dbg).do_cmd(&["continue"]).unwrap();
+        assert!(matches!(state, State::NotRunning(_)));
+    }
+
+    #[test]
+    fn breakpoint_hit_keeps_bytes_written_after_insertion() {
+        // ブレークポイントのnopの直後のquadを書き換えてからnopを実行する
+        // テストはブレークポイントで停止するまでのみ実行する
+        let asm = r#"
+    .globl _start
+    .section .rwx, "awx"
+_start:
+    movabs $0x1122334455667788, %rax
+    mov %rax, val(%rip)
+brk:
+    nop
+val:
+    .quad 0
+"#;
+        let bin = build("breakpoint_hit_keeps_bytes_written_after_insertion", asm, &["-static"]);
+        let entry = entry(&bin);
+        let brk = entry + 0x11;
+        let mut dbg = ZDbg::new(bin.to_str().unwrap().to_string());
+        dbg.do_break(&["break", &format!("{:#x}", brk)]);
+
+        // 元に戻すのは"int 3"の1バイトのみで､子プロセスが書き込んだ値は残る
+        let dbg = running(State::NotRunning(dbg), &["run"]);
+        assert_eq!(rip(&dbg), brk);
+        assert_eq!(dbg.read_bytes(brk, 1).unwrap(), [0x90]);
+        assert_eq!(dbg.read_bytes(brk + 1, 8).unwrap(), 0x1122334455667788u64.to_le_bytes());
+    }
+
+    #[test]
+    fn single_step_trap_is_not_rewound() {
+        let (dbg, entry) = inferior("single_step_trap_is_not_rewound");
+        let mut dbg = running(State::NotRunning(dbg), &["starti"]);
+
+        // "int 3"を挿入していないブレークポイントのアドレスからシングルステップすると
+        // ripはその次を指すが､TRAP_TRACEのためブレークポイントへのヒットとは扱わない
+        dbg.info.brk_addr = Some(entry as *mut c_void);
+        ptrace::step(dbg.info.pid, None).unwrap();
+        let mut dbg = match dbg.wait_child().unwrap() {
+            State::Running(r) => r,
+            _ => panic!("stepiの後に子プロセスが停止していません"),
+        };
+        assert!(!dbg.is_brk_trap().unwrap());
+        assert_eq!(rip(&dbg), entry + 0x01);
+
+        // "int 3"によるSIGTRAPの場合はripを1つ戻す
+        dbg.info.brk_addr = None;
+        dbg.do_break(&["break", &format!("{:#x}", entry + 0x02)]).unwrap();
+        let dbg = running(State::Running(dbg), &["continue"]);
+        assert!(dbg.is_brk_trap().unwrap());
+        assert_eq!(rip(&dbg), entry + 0x02);
+    }
+
+    #[test]
+    fn stepi_onto_and_off_breakpoint() {
+        let (mut dbg, entry) = inferior("stepi_onto_and_off_breakpoint");
+        let brk = entry + 0x01;
+        dbg.do_break(&["break", &format!("{:#x}", brk)]);
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+
+        // ステップでブレークポイントに到達しても"int 3"は挿入されたまま
+        let dbg = running(State::Running(dbg), &["stepi"]);
+        assert_eq!(rip(&dbg), brk);
+        assert_eq!(dbg.read_bytes(brk, 1).unwrap(), [0xcc]);
+
+        // 次のstepiで同じアドレスに再度停止せず､元の命令を実行して進む
+        let dbg = running(State::Running(dbg), &["stepi"]);
+        assert_eq!(rip(&dbg), entry + 0x02);
+        assert_eq!(dbg.read_bytes(brk, 1).unwrap(), [0xcc]);
+
+        // 通過済みのブレークポイントでは止まらずに終了する
+        let state = State::Running(dbg).do_cmd(&["continue"]).unwrap();
+        assert!(matches!(state, State::NotRunning(_)));
+    }
+
+    #[test]
+    fn drop_running_reaps_child() {
+        let (dbg, _) = inferior("drop_running_reaps_child");
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        let pid = dbg.info.pid;
+
+        drop(dbg);
+
+        // 回収済みであれば子プロセスは残っていない
+        assert_eq!(waitpid(pid, Some(WaitPidFlag::WNOHANG)), Err(Errno::ECHILD));
+    }
+
+    #[test]
+    fn failed_spawn_reaps_child() {
+        let (dbg, _) = inferior("failed_spawn_reaps_child");
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        let pid = dbg.info.pid;
+        std::mem::forget(dbg);
+
+        // 最初の停止の処理に失敗した場合も､停止したままの子プロセスを残さない
+        let dbg = ZDbg::new("failed_spawn_reaps_child".to_string());
+        assert!(dbg.on_spawned(Running { pid }, WaitStatus::StillAlive, -1).is_err());
+        assert_eq!(waitpid(pid, Some(WaitPidFlag::WNOHANG)), Err(Errno::ECHILD));
+    }
+
+    #[test]
+    fn spawn_stopped_before_exec_does_not_read_pipe() {
+        let (pipe_r, pipe_w) = pipe2(OFlag::O_CLOEXEC).unwrap();
+
+        // traceme後､exec前にシグナルで停止した子プロセス
+        // パイプの書き込み側は開いたままになる
+        let pid = match unsafe { fork().unwrap() } {
+            ForkResult::Child => {
+                let _ = ptrace::traceme();
+                let _ = signal::raise(Signal::SIGUSR1);
+                unsafe { _exit(0) };
+            }
+            ForkResult::Parent { child } => child,
+        };
+        let status = waitpid(pid, None).unwrap();
+        assert_eq!(status, WaitStatus::Stopped(pid, Signal::SIGUSR1));
+
+        // パイプを読み込まずに戻る
+        let mut dbg = ZDbg::new("spawn_stopped_before_exec_does_not_read_pipe".to_string());
+        dbg.info.pid = pid;
+        assert!(dbg.on_spawned(Running { pid }, status, pipe_r).is_ok());
+
+        let _ = close(pipe_r);
+        let _ = close(pipe_w);
+    }
+
+    #[test]
+    fn starti_records_regs_and_hits_entry_breakpoint() {
+        let (mut dbg, entry) = inferior("starti_records_regs_and_hits_entry_breakpoint");
+        dbg.do_break(&["break", &format!("{:#x}", entry)]);
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+
+        // startiの停止位置が次のstepiでの比較対象になる
+        assert_eq!(dbg.info.stop_regs.map(|r| r.rip), Some(entry));
+
+        // エントリポイントのブレークポイントは通過されずにヒットする
+        let dbg = running(State::Running(dbg), &["continue"]);
+        assert_eq!(rip(&dbg), entry);
+        assert_eq!(dbg.info.prev_regs.map(|r| r.rip), Some(entry));
+    }
+
+    #[test]
+    fn check_target_leaves_non_elf_to_execvp() {
+        let dir = std::env::temp_dir().join(format!("zdbg-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // ELFヘッダより短いものと長いものの#!スクリプトはいずれも実行できる
+        let scripts = [("short.sh", "#!/bin/true\n"), ("long.sh", "#!/bin/sh\nexit 0\n# padding\n")];
+        for (name, script) in scripts {
+            let path = dir.join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            let dbg = ZDbg::new(path.to_str().unwrap().to_string());
+            assert!(dbg.check_target(), "{name}");
+            running(State::NotRunning(dbg), &["starti"]);
+        }
+
+        // PEやMach-Oは/bin/shにスクリプトとして実行させず中止する
+        let bins: [(&str, &[u8]); 2] = [("pe.exe", b"MZ\x90\x00"), ("macho", b"\xcf\xfa\xed\xfe")];
+        for (name, magic) in bins {
+            let path = dir.join(name);
+            fs::write(&path, magic).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            let dbg = ZDbg::new(path.to_str().unwrap().to_string());
+            assert!(!dbg.check_target(), "{name}");
+            let state = State::NotRunning(dbg).do_cmd(&["run"]).unwrap();
+            assert!(matches!(state, State::NotRunning(_)), "{name}");
+        }
+
+        // ELFでもx86-64以外向けのものは実行しない
+        let (dbg, _) = inferior("check_target_leaves_non_elf_to_execvp");
+        let mut bytes = fs::read(&dbg.info.filename).unwrap();
+        bytes[18..20].copy_from_slice(&183u16.to_le_bytes()); // AArch64
+        let path = dir.join("aarch64");
+        fs::write(&path, bytes).unwrap();
+        assert!(!ZDbg::new(path.to_str().unwrap().to_string()).check_target());
+    }
+
+    #[test]
+    fn stop_hook_splits_on_semicolon() {
+        let mut dbg = ZDbg::new("stop_hook_splits_on_semicolon".to_string());
+
+        // ;の前後に空白がない場合も別のコマンドとして登録する
+        dbg.do_set(&["set", "stop-hook", "regs;", "vmmap", "0x0;context"]);
+        assert_eq!(dbg.info.stop_hook, ["regs", "vmmap 0x0", "context"]);
+        dbg.do_set(&["set", "stop-hook", "regs", ";", "tel", "rsp", "4"]);
+        assert_eq!(dbg.info.stop_hook, ["regs", "tel rsp 4"]);
+
+        // 不明なコマンドや実行を再開するコマンドを含む場合は変更しない
+        dbg.do_set(&["set", "stop-hook", "regs;", "foo"]);
+        dbg.do_set(&["set", "stop-hook", "regs;stepi"]);
+        assert_eq!(dbg.info.stop_hook, ["regs", "tel rsp 4"]);
+    }
+
+    #[test]
+    fn is_after_call_checks_instruction_length() {
+        let after = |tail: &[u8]| {
+            let mut code = [0x90; 8];
+            code[8 - tail.len()..].copy_from_slice(tail);
+            is_after_call(&code)
+        };
+
+        assert!(after(&[0xe8, 0, 0, 0, 0])); // call rel32
+        assert!(after(&[0xff, 0xd0])); // call rax
+        assert!(after(&[0xff, 0x10])); // call [rax]
+        assert!(after(&[0xff, 0x50, 0x08])); // call [rax+8]
+        assert!(after(&[0xff, 0x15, 0, 0, 0, 0])); // call [rip+disp32]
+        assert!(after(&[0xff, 0x14, 0x24])); // call [rsp]
+        assert!(after(&[0xff, 0x54, 0x24, 0x08])); // call [rsp+8]
+        assert!(after(&[0xff, 0x94, 0xc8, 0, 0, 0, 0])); // call [rax+rcx*8+disp32]
+        assert!(after(&[0xff, 0x14, 0xc5, 0, 0, 0, 0])); // call [rax*8+disp32]
+
+        // regが2でも命令長が戻りアドレスと合わないものは除外
+        assert!(!after(&[0xff, 0x10, 0x00])); // call [rax]の後に1バイト
+        assert!(!after(&[0xff, 0xd0, 0x00, 0x00])); // call raxの後に2バイト
+        assert!(!after(&[0xff, 0x15, 0, 0])); // disp32が足りない
+        assert!(!after(&[0xff, 0x54, 0x24])); // disp8が足りない
+    }
+
+    #[test]
+    fn inferior_tty_becomes_controlling_terminal() {
+        let pty = openpty(None, None).unwrap();
+        let path = ttyname(pty.slave).unwrap();
+        let (mut dbg, _) = inferior("inferior_tty_becomes_controlling_terminal");
+        dbg.do_set(&["set", "inferior-tty", path.to_str().unwrap()]);
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+
+        // /proc/<pid>/statの6番目がセッションID､7番目が制御端末のデバイス番号
+        let stat = fs::read_to_string(format!("/proc/{}/stat", dbg.info.pid)).unwrap();
+        let fields: Vec<&str> = stat.rsplit_once(')').unwrap().1.split_whitespace().collect();
+        assert_eq!(fields[3], dbg.info.pid.to_string());
+        let rdev = fs::metadata(&path).unwrap().rdev();
+        assert_eq!(fields[4].parse::<u64>().unwrap(), rdev);
+
+        let _ = close(pty.master);
+        let _ = close(pty.slave);
+    }
+
+    #[test]
+    fn run_reports_missing_interpreter() {
+        // 存在しない動的リンカを要求する実行ファイル
+        let interp = r#"
+    .section .interp, "a"
+    .string "/nonexistent/ld.so"
+"#;
+        let asm = format!("{INFERIOR}{interp}");
+        let bin = build("run_reports_missing_interpreter", &asm, &[]);
+        let dbg = ZDbg::new(bin.to_str().unwrap().to_string());
+
+        let e = match State::NotRunning(dbg).do_cmd(&["run"]) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("実行に成功してしまいました"),
+        };
+        assert!(e.contains("execvpに失敗 : ENOENT"), "{e}");
+    }
+
+    #[test]
+    fn exec_wrapper_follows_into_program() {
+        // /bin/trueをexecする
+        let asm = r#"
+    .globl _start
+    .text
+_start:
+    nop
+    lea path(%rip), %rdi
+    xor %esi, %esi
+    xor %edx, %edx
+    mov $59, %eax
+    syscall
+    .data
+path:
+    .string "/bin/true"
+"#;
+        let bin = build("exec_wrapper_follows_into_program", asm, &["-static"]);
+        let entry = entry(&bin);
+        let mut dbg = ZDbg::new(bin.to_str().unwrap().to_string());
+        dbg.do_break(&["break", &format!("{:#x}", entry)]);
+        dbg.do_set(&["set", "exec-wrapper", "env", "FOO=1"]);
+
+        // ブレークポイントはラッパーではなく対象のプログラムに設定される
+        let dbg = running(State::NotRunning(dbg), &["run"]);
+        assert_eq!(rip(&dbg), entry);
+
+        // 対象のプログラムがexecした後も追跡を続けられる
+        let dbg = running(State::Running(dbg), &["continue"]);
+        let path = fs::read_link(format!("/proc/{}/exe", dbg.info.pid)).unwrap();
+        assert_eq!(path, fs::canonicalize("/bin/true").unwrap());
+    }
+
+    #[test]
+    fn warn_if_readonly_text_and_data() {
+        let (dbg, entry) = inferior("warn_if_readonly_text_and_data");
+        let mut dbg = running(State::NotRunning(dbg), &["starti"]);
+        let data = data_addr(&dbg);
+
+        assert!(dbg.warn_if_readonly(entry, 1).unwrap());
+        assert!(!dbg.warn_if_readonly(data, 8).unwrap());
+
+        // 警告のみで書き込み自体はどちらも行われる
+        dbg.patch_bytes(entry, &[0xcc]).unwrap();
+        assert_eq!(dbg.read_bytes(entry, 1).unwrap(), [0xcc]);
+        dbg.patch_bytes(data, &[0xaa; 8]).unwrap();
+        assert_eq!(dbg.read_bytes(data, 8).unwrap(), [0xaa; 8]);
+
+        // 末尾がアドレス空間を超えてもpanicしない
+        assert!(!dbg.warn_if_readonly(u64::MAX - 1, 4).unwrap());
+    }
+
+    #[test]
+    fn save_and_diff_registers_report_errors() {
+        let (dbg, entry) = inferior("save_and_diff_registers_report_errors");
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        let entry = format!("{:#x}", entry);
+
+        // 失敗はいずれも表示のみで､デバッガは実行中のまま
+        let dbg = running(State::Running(dbg), &["diff-registers", "/nonexistent/regs"]);
+        let dbg = running(State::Running(dbg), &["save", "registers", "/nonexistent/regs"]);
+        let dbg = running(State::Running(dbg), &["save", "memory", "0x10", "8", "/dev/null"]);
+        let dbg = running(
+            State::Running(dbg),
+            &["save", "memory", &entry, "99999999999999", "/dev/null"],
+        );
+        let dbg = running(
+            State::Running(dbg),
+            &["save", "memory", "0xfffffffffffffffe", "4", "/dev/null"],
+        );
+
+        assert!(dbg.read_bytes(u64::MAX - 1, 4).is_err());
+        assert!(dbg.read_bytes(0, READ_MAX + 1).is_err());
+    }
+
+    #[test]
+    fn telescope_annotates_strings() {
+        let (dbg, entry) = inferior("telescope_annotates_strings");
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+
+        // argv[0]にはstartiのコマンド名がそのまま渡る
+        let sp = dbg.info.entry_sp.unwrap();
+        let argv0 = u64::from_le_bytes(dbg.read_bytes(sp + 8, 8).unwrap().try_into().unwrap());
+        assert_eq!(dbg.read_printable(argv0).as_deref(), Some("\"starti\""));
+
+        // 機械語は文字列とは扱わない
+        assert_eq!(dbg.read_printable(entry), None);
+    }
+
+    #[test]
+    fn patch_with_breakpoint_at_end_of_address_space() {
+        let (mut dbg, entry) = inferior("patch_with_breakpoint_at_end_of_address_space");
+        dbg.do_break(&["break", "0xfffffffffffffffc"]);
+        let mut dbg = running(State::NotRunning(dbg), &["starti"]);
+
+        // ブレークポイントのアドレスとの比較であふれない
+        dbg.patch_bytes(entry, &[0x90]).unwrap();
+        assert_eq!(dbg.read_bytes(entry, 2).unwrap(), [0x90, 0x90]);
+
+        // 書き込み範囲がアドレス空間の終端を超える場合はエラー
+        assert!(dbg.write_bytes(u64::MAX - 1, &[0x90; 4]).is_err());
+    }
+
+    #[test]
+    fn rerun_forgets_previous_registers() {
+        let (dbg, _) = inferior("rerun_forgets_previous_registers");
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        let dbg = running(State::Running(dbg), &["stepi"]);
+        let dbg = running(State::Running(dbg), &["stepi"]);
+        let dbg = match State::Running(dbg).do_cmd(&["continue"]).unwrap() {
+            State::NotRunning(n) => n,
+            _ => panic!("continueの後に子プロセスが終了していません"),
+        };
+
+        // 新しいプロセスの最初の停止では比較対象の前回のレジスタはない
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        assert!(dbg.info.prev_regs.is_none());
+    }
+
+    #[test]
+    fn argv_survives_clobbered_initial_stack() {
+        let (dbg, _) = inferior("argv_survives_clobbered_initial_stack");
+        let mut dbg = running(State::NotRunning(dbg), &["starti"]);
+        let sp = dbg.info.entry_sp.unwrap();
+
+        // 読み込めないポインタやアドレス空間の終端付近のポインタでも終了しない
+        for p in [0x10u64, 0xfffffffffffffff0] {
+            dbg.write_bytes(sp + 8, &p.to_le_bytes()).unwrap();
+            assert_eq!(dbg.read_cstr_or_invalid(p), "<invalid>");
+            dbg = running(State::Running(dbg), &["argv"]);
+        }
+
+        // argcが書き換えられた場合はスタックの終端で打ち切る
+        dbg.write_bytes(sp, &u64::MAX.to_le_bytes()).unwrap();
+        let stack = dbg.read_initial_stack().unwrap();
+        assert_eq!(stack.argc, Some(u64::MAX));
+        assert!(stack.envp.is_empty() && stack.auxv.is_empty());
+        let dbg = running(State::Running(dbg), &["argv"]);
+        running(State::Running(dbg), &["auxv"]);
+    }
+
+    #[test]
+    fn clear_removes_breakpoint() {
+        let (dbg, entry) = inferior("clear_removes_breakpoint");
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        let movq = format!("{:#x}", entry + 0x02);
+
+        // "int 3"を元の値に戻し､continueでそのアドレスを通過して終了する
+        let dbg = running(State::Running(dbg), &["break", &movq]);
+        assert_eq!(dbg.read_bytes(entry + 0x02, 1).unwrap(), [0xcc]);
+        let dbg = running(State::Running(dbg), &["clear", &movq]);
+        assert_eq!(dbg.read_bytes(entry + 0x02, 1).unwrap(), [0x48]);
+        let state = State::Running(dbg).do_cmd(&["continue"]).unwrap();
+        assert!(matches!(state, State::NotRunning(_)));
+
+        // 書き込めなかったブレークポイントの削除ではメモリに触れない
+        let mut dbg = match state {
+            State::NotRunning(n) => n,
+            _ => unreachable!(),
+        };
+        dbg.do_break(&["break", "0x10"]);
+        let dbg = running(State::NotRunning(dbg), &["starti"]);
+        let dbg = running(State::Running(dbg), &["clear", "0x10"]);
+        assert!(dbg.info.brk_addr.is_none());
+    }
+}
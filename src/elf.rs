@@ -0,0 +1,76 @@
+use std::{env, fs::File, io::Read, path::PathBuf};
+
+/// ELFヘッダのうちデバッガが確認する項目
+pub struct ElfHeader {
+    pub class: u8,    // 1 = 32ビット, 2 = 64ビット
+    pub osabi: u8,    // 0 = System V, 3 = Linux
+    pub machine: u16, // 62 = x86-64
+}
+
+pub const ELFCLASS64: u8 = 2;
+pub const ELFOSABI_SYSV: u8 = 0;
+pub const ELFOSABI_LINUX: u8 = 3;
+pub const EM_X86_64: u16 = 62;
+
+impl ElfHeader {
+    /// OS ABIの名前
+    pub fn osabi_name(&self) -> &'static str {
+        match self.osabi {
+            0 => "System V",
+            1 => "HP-UX",
+            2 => "NetBSD",
+            3 => "Linux",
+            6 => "Solaris",
+            9 => "FreeBSD",
+            12 => "OpenBSD",
+            _ => "不明",
+        }
+    }
+}
+
+/// 実行ファイルの先頭から判別した種類
+pub enum Executable {
+    Elf(ElfHeader),
+    Script,     // #!で始まるスクリプト
+    NotElf,     // Mach-OやPEなど､ELFでもスクリプトでもないもの
+    Unreadable, // 開けない､または読み込めない (実行権限のみの場合など)
+}
+
+/// 実行ファイルの先頭を読み込んで種類を判別
+pub fn read_executable(path: &PathBuf) -> Executable {
+    let mut buf = Vec::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Executable::Unreadable,
+    };
+    if file.take(20).read_to_end(&mut buf).is_err() {
+        return Executable::Unreadable;
+    }
+
+    if buf.starts_with(b"#!") {
+        return Executable::Script;
+    }
+    if !buf.starts_with(b"\x7fELF") || buf.len() < 20 {
+        return Executable::NotElf;
+    }
+
+    Executable::Elf(ElfHeader {
+        class: buf[4],
+        osabi: buf[7],
+        machine: u16::from_le_bytes([buf[18], buf[19]]),
+    })
+}
+
+/// execvpと同様に実行ファイルのパスを解決
+/// /を含む場合はそのまま､含まない場合はPATHから探す
+pub fn find_executable(filename: &str) -> Option<PathBuf> {
+    if filename.contains('/') {
+        return Some(PathBuf::from(filename));
+    }
+
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(filename))
+            .find(|p| p.is_file())
+    })
+}
@@ -1,6 +1,55 @@
 mod dbg;
+mod elf;
 mod helper;
+mod maps;
+mod procfs;
 
-fn main() {
-    println!("Hello, world!");
+use dbg::{State, ZDbg};
+use helper::DynError;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::env::args;
+
+fn main() -> Result<(), DynError> {
+    let args: Vec<String> = args().collect();
+    if args.len() < 2 {
+        let msg = format!("引数が必要です\n例 : {} 実行ファイル [引数*]", args[0]);
+        return Err(msg.into());
+    }
+
+    // エラーやpanicで終了した場合も､実行中の子プロセスはZDbg<Running>の破棄時に回収される
+    run_dbg(&args[1])?;
+    Ok(())
+}
+
+fn run_dbg(filename: &str) -> Result<(), DynError> {
+    let debugger = ZDbg::new(filename.to_string());
+    let mut state = State::NotRunning(debugger);
+    let mut rl = DefaultEditor::new()?;
+
+    loop {
+        match rl.readline("zdbg > ") {
+            Ok(line) => {
+                let trimed = line.trim(); // 行頭と行末の空白文字を削除
+                let cmd: Vec<&str> = trimed.split(' ').filter(|c| !c.is_empty()).collect();
+                state = match state {
+                    State::Exit => break,
+                    s => s.do_cmd(&cmd)?,
+                };
+                if let State::Exit = state {
+                    break;
+                }
+                rl.add_history_entry(line)?;
+            }
+            Err(ReadlineError::Interrupted) => eprintln!("<<終了はCtrl+D>>"),
+            _ => {
+                if let State::Running(r) = state {
+                    // 子プロセスが実行中の場合はkill
+                    r.do_exit()?;
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,54 @@
+use std::fs;
+
+use nix::unistd::Pid;
+
+use crate::helper::DynError;
+
+/// /proc/<pid>/mapsの1行分の情報
+pub struct MapEntry {
+    pub start: u64,
+    pub end: u64,
+    pub perms: String,
+    pub offset: u64,
+    pub path: String,
+}
+
+impl MapEntry {
+    /// addrがこの領域に含まれるか
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// 表示用の1行
+    pub fn to_line(&self) -> String {
+        format!(
+            "{:#014x}-{:#014x} {} {:#010x} {}",
+            self.start, self.end, self.perms, self.offset, self.path
+        )
+    }
+}
+
+/// /proc/<pid>/mapsを読み込みパース
+pub fn read_maps(pid: Pid) -> Result<Vec<MapEntry>, DynError> {
+    let text = fs::read_to_string(format!("/proc/{pid}/maps"))?;
+    text.lines().map(parse_line).collect()
+}
+
+/// 例: 555555554000-555555555000 r--p 00000000 08:01 1234 /usr/bin/ls
+fn parse_line(line: &str) -> Result<MapEntry, DynError> {
+    let mut fields = line.split_whitespace();
+    let range = fields.next().ok_or("mapsの形式が不正です")?;
+    let perms = fields.next().ok_or("mapsの形式が不正です")?;
+    let offset = fields.next().ok_or("mapsの形式が不正です")?;
+    let path = fields.skip(2).collect::<Vec<_>>().join(" ");
+
+    let (start, end) = range.split_once('-').ok_or("mapsの形式が不正です")?;
+
+    Ok(MapEntry {
+        start: u64::from_str_radix(start, 16)?,
+        end: u64::from_str_radix(end, 16)?,
+        perms: perms.to_string(),
+        offset: u64::from_str_radix(offset, 16)?,
+        path,
+    })
+}
@@ -0,0 +1,44 @@
+use std::fs;
+
+use nix::unistd::Pid;
+
+use crate::helper::DynError;
+
+/// /proc/<pid>/statusを読み込み､項目名と値の組にパース
+pub fn read_status(pid: Pid) -> Result<Vec<(String, String)>, DynError> {
+    let text = fs::read_to_string(format!("/proc/{pid}/status"))?;
+    let fields = text
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, val)| (key.to_string(), val.trim().to_string()))
+        .collect();
+    Ok(fields)
+}
+
+/// /proc/<pid>/taskからスレッドIDの一覧を取得
+pub fn read_tasks(pid: Pid) -> Result<Vec<i32>, DynError> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(format!("/proc/{pid}/task"))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+            tids.push(tid);
+        }
+    }
+    tids.sort();
+    Ok(tids)
+}
+
+/// 各スレッドの/proc/<pid>/task/<tid>/childrenから子プロセスのPIDを取得
+/// カーネルがchildrenに対応していない場合は空
+pub fn read_children(pid: Pid, tids: &[i32]) -> Vec<i32> {
+    let mut children: Vec<i32> = tids
+        .iter()
+        .filter_map(|tid| fs::read_to_string(format!("/proc/{pid}/task/{tid}/children")).ok())
+        .flat_map(|text| {
+            text.split_whitespace()
+                .filter_map(|s| s.parse().ok())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    children.sort();
+    children
+}